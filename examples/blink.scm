@@ -0,0 +1,19 @@
+; counts up on port b
+;   uokichi examples/blink.scm -o blink.hex
+
+(org #x0000)
+(equ DDRB #x17)
+(equ PORTB #x18)
+(def temp r16)
+(def count r17)
+
+(label reset)
+(ldi temp #xff)
+(out DDRB temp)
+(clr count)
+
+(label loop)
+(inc count)
+(out PORTB count)
+(rjmp loop)
+(jmp reset)
@@ -0,0 +1,338 @@
+//! the AVR instruction set and its compile settings
+
+use crate::compile::{AddressUnit, ByteOrder, CompileSettings, Section};
+use crate::hexfmt::HexFormat;
+use crate::isa::{ArgKind, Isa, Opdef, Transform};
+
+/// flash is 16 bit words, stored low byte first
+///   jumps and labels count in words
+pub fn compile_settings() -> CompileSettings {
+    CompileSettings {
+        opcode_size: 2,
+        byte_order: ByteOrder::Little,
+        address_unit: AddressUnit::Word,
+        address_size: 2,
+        words_per_record: 8,
+        hex_format: HexFormat::I32,
+        // erased flash
+        bin_fill: 0xff,
+        output_section: Some(Section::Text),
+        // EM_AVR
+        elf_machine: 83,
+        // avr5, the core of the atmega328p
+        elf_flags: 5,
+        ..CompileSettings::default()
+    }
+}
+
+// bit patterns are from the AVR instruction set manual
+//   d, r: registers
+//   K: immediate, k: address, A: io address
+//   b: bit number, s: sreg bit, q: displacement
+
+/// every avr instruction, and the aliases avr-as accepts
+pub fn instruction_set() -> Isa {
+    use ArgKind::*;
+    use Transform::*;
+
+    let pairs = || Lookup(vec![24, 26, 28, 30]);
+
+    let opdefs = vec![
+        // arithmetic and logic
+        Opdef::new("add",    "000011rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("adc",    "000111rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("adiw",   "10010110KKddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, pairs()),
+        Opdef::new("sub",    "000110rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("subi",   "0101KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("sbc",    "000010rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("sbci",   "0100KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("sbiw",   "10010111KKddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, pairs()),
+        Opdef::new("and",    "001000rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("andi",   "0111KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("or",     "001010rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("ori",    "0110KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("eor",    "001001rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("com",    "1001010ddddd0000", "d",   &[Register]),
+        Opdef::new("neg",    "1001010ddddd0001", "d",   &[Register]),
+        Opdef::new("sbr",    "0110KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("cbr",    "0111KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Complement(8)),
+        Opdef::new("inc",    "1001010ddddd0011", "d",   &[Register]),
+        Opdef::new("dec",    "1001010ddddd1010", "d",   &[Register]),
+        Opdef::new("tst",    "001000rdddddrrrr", "d=r", &[Register]),
+        Opdef::new("clr",    "001001rdddddrrrr", "d=r", &[Register]),
+        Opdef::new("ser",    "11101111dddd1111", "d",   &[Register])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("mul",    "100111rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("muls",   "00000010ddddrrrr", "dr",  &[Register, Register])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Subtract(16)),
+        Opdef::new("mulsu",  "000000110ddd0rrr", "dr",  &[Register, Register])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Subtract(16)),
+        Opdef::new("fmul",   "000000110ddd1rrr", "dr",  &[Register, Register])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Subtract(16)),
+        Opdef::new("fmuls",  "000000111ddd0rrr", "dr",  &[Register, Register])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Subtract(16)),
+        Opdef::new("fmulsu", "000000111ddd1rrr", "dr",  &[Register, Register])
+            .with_transform(0, Subtract(16))
+            .with_transform(1, Subtract(16)),
+        Opdef::new("des",    "10010100KKKK1011", "K",   &[Immediate]),
+
+        // branches
+        Opdef::new("rjmp",   "1100kkkkkkkkkkkk", "k",   &[Relative])
+            .with_transform(0, TwosComplement(12)),
+        Opdef::new("ijmp",   "1001010000001001", "",    &[]),
+        Opdef::new("eijmp",  "1001010000011001", "",    &[]),
+        Opdef::new("jmp",    "1001010kkkkk110kkkkkkkkkkkkkkkkk", "k", &[Absolute]),
+        Opdef::new("rcall",  "1101kkkkkkkkkkkk", "k",   &[Relative])
+            .with_transform(0, TwosComplement(12)),
+        Opdef::new("icall",  "1001010100001001", "",    &[]),
+        Opdef::new("eicall", "1001010100011001", "",    &[]),
+        Opdef::new("call",   "1001010kkkkk111kkkkkkkkkkkkkkkkk", "k", &[Absolute]),
+        Opdef::new("ret",    "1001010100001000", "",    &[]),
+        Opdef::new("reti",   "1001010100011000", "",    &[]),
+        Opdef::new("cpse",   "000100rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("cp",     "000101rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("cpc",    "000001rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("cpi",    "0011KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("sbrc",   "1111110rrrrr0bbb", "rb",  &[Register, Immediate]),
+        Opdef::new("sbrs",   "1111111rrrrr0bbb", "rb",  &[Register, Immediate]),
+        Opdef::new("sbic",   "10011001AAAAAbbb", "Ab",  &[Address, Immediate]),
+        Opdef::new("sbis",   "10011011AAAAAbbb", "Ab",  &[Address, Immediate]),
+        Opdef::new("brbs",   "111100kkkkkkksss", "sk",  &[Immediate, Relative])
+            .with_transform(1, TwosComplement(7)),
+        Opdef::new("brbc",   "111101kkkkkkksss", "sk",  &[Immediate, Relative])
+            .with_transform(1, TwosComplement(7)),
+        Opdef::new("breq",   "111100kkkkkkk001", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brne",   "111101kkkkkkk001", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brcs",   "111100kkkkkkk000", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brcc",   "111101kkkkkkk000", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brsh",   "111101kkkkkkk000", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brlo",   "111100kkkkkkk000", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brmi",   "111100kkkkkkk010", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brpl",   "111101kkkkkkk010", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brge",   "111101kkkkkkk100", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brlt",   "111100kkkkkkk100", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brhs",   "111100kkkkkkk101", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brhc",   "111101kkkkkkk101", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brts",   "111100kkkkkkk110", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brtc",   "111101kkkkkkk110", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brvs",   "111100kkkkkkk011", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brvc",   "111101kkkkkkk011", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brie",   "111100kkkkkkk111", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+        Opdef::new("brid",   "111101kkkkkkk111", "k",   &[Relative])
+            .with_transform(0, TwosComplement(7)),
+
+        // data transfer
+        Opdef::new("mov",    "001011rdddddrrrr", "dr",  &[Register, Register]),
+        Opdef::new("movw",   "00000001ddddrrrr", "dr",  &[Register, Register])
+            .with_transform(0, Divide(2))
+            .with_transform(1, Divide(2)),
+        Opdef::new("ldi",    "1110KKKKddddKKKK", "dK",  &[Register, Immediate])
+            .with_transform(0, Subtract(16)),
+        Opdef::new("lds",    "1001000ddddd0000kkkkkkkkkkkkkkkk", "dk", &[Register, Address]),
+        Opdef::new("ld",     "1001000ddddd1100", "d_",  &[Register, Pointer("X")]),
+        Opdef::new("ld",     "1001000ddddd1101", "d_",  &[Register, Pointer("X+")]),
+        Opdef::new("ld",     "1001000ddddd1110", "d_",  &[Register, Pointer("-X")]),
+        Opdef::new("ld",     "1000000ddddd1000", "d_",  &[Register, Pointer("Y")]),
+        Opdef::new("ld",     "1001000ddddd1001", "d_",  &[Register, Pointer("Y+")]),
+        Opdef::new("ld",     "1001000ddddd1010", "d_",  &[Register, Pointer("-Y")]),
+        Opdef::new("ld",     "1000000ddddd0000", "d_",  &[Register, Pointer("Z")]),
+        Opdef::new("ld",     "1001000ddddd0001", "d_",  &[Register, Pointer("Z+")]),
+        Opdef::new("ld",     "1001000ddddd0010", "d_",  &[Register, Pointer("-Z")]),
+        Opdef::new("ldd",    "10q0qq0ddddd1qqq", "d_q", &[Register, Pointer("Y"), Immediate]),
+        Opdef::new("ldd",    "10q0qq0ddddd0qqq", "d_q", &[Register, Pointer("Z"), Immediate]),
+        Opdef::new("sts",    "1001001rrrrr0000kkkkkkkkkkkkkkkk", "kr", &[Address, Register]),
+        Opdef::new("st",     "1001001rrrrr1100", "_r",  &[Pointer("X"), Register]),
+        Opdef::new("st",     "1001001rrrrr1101", "_r",  &[Pointer("X+"), Register]),
+        Opdef::new("st",     "1001001rrrrr1110", "_r",  &[Pointer("-X"), Register]),
+        Opdef::new("st",     "1000001rrrrr1000", "_r",  &[Pointer("Y"), Register]),
+        Opdef::new("st",     "1001001rrrrr1001", "_r",  &[Pointer("Y+"), Register]),
+        Opdef::new("st",     "1001001rrrrr1010", "_r",  &[Pointer("-Y"), Register]),
+        Opdef::new("st",     "1000001rrrrr0000", "_r",  &[Pointer("Z"), Register]),
+        Opdef::new("st",     "1001001rrrrr0001", "_r",  &[Pointer("Z+"), Register]),
+        Opdef::new("st",     "1001001rrrrr0010", "_r",  &[Pointer("-Z"), Register]),
+        Opdef::new("std",    "10q0qq1rrrrr1qqq", "_qr", &[Pointer("Y"), Immediate, Register]),
+        Opdef::new("std",    "10q0qq1rrrrr0qqq", "_qr", &[Pointer("Z"), Immediate, Register]),
+        Opdef::new("lpm",    "1001010111001000", "",    &[]),
+        Opdef::new("lpm",    "1001000ddddd0100", "d_",  &[Register, Pointer("Z")]),
+        Opdef::new("lpm",    "1001000ddddd0101", "d_",  &[Register, Pointer("Z+")]),
+        Opdef::new("elpm",   "1001010111011000", "",    &[]),
+        Opdef::new("elpm",   "1001000ddddd0110", "d_",  &[Register, Pointer("Z")]),
+        Opdef::new("elpm",   "1001000ddddd0111", "d_",  &[Register, Pointer("Z+")]),
+        Opdef::new("spm",    "1001010111101000", "",    &[]),
+        Opdef::new("spm",    "1001010111111000", "_",   &[Pointer("Z+")]),
+        Opdef::new("in",     "10110AAdddddAAAA", "dA",  &[Register, Address]),
+        Opdef::new("out",    "10111AArrrrrAAAA", "Ar",  &[Address, Register]),
+        Opdef::new("push",   "1001001ddddd1111", "d",   &[Register]),
+        Opdef::new("pop",    "1001000ddddd1111", "d",   &[Register]),
+        Opdef::new("xch",    "1001001rrrrr0100", "_r",  &[Pointer("Z"), Register]),
+        Opdef::new("las",    "1001001rrrrr0101", "_r",  &[Pointer("Z"), Register]),
+        Opdef::new("lac",    "1001001rrrrr0110", "_r",  &[Pointer("Z"), Register]),
+        Opdef::new("lat",    "1001001rrrrr0111", "_r",  &[Pointer("Z"), Register]),
+
+        // bit and bit-test
+        Opdef::new("lsl",    "000011rdddddrrrr", "d=r", &[Register]),
+        Opdef::new("lsr",    "1001010ddddd0110", "d",   &[Register]),
+        Opdef::new("rol",    "000111rdddddrrrr", "d=r", &[Register]),
+        Opdef::new("ror",    "1001010ddddd0111", "d",   &[Register]),
+        Opdef::new("asr",    "1001010ddddd0101", "d",   &[Register]),
+        Opdef::new("swap",   "1001010ddddd0010", "d",   &[Register]),
+        Opdef::new("bset",   "100101000sss1000", "s",   &[Immediate]),
+        Opdef::new("bclr",   "100101001sss1000", "s",   &[Immediate]),
+        Opdef::new("sbi",    "10011010AAAAAbbb", "Ab",  &[Address, Immediate]),
+        Opdef::new("cbi",    "10011000AAAAAbbb", "Ab",  &[Address, Immediate]),
+        Opdef::new("bst",    "1111101ddddd0bbb", "db",  &[Register, Immediate]),
+        Opdef::new("bld",    "1111100ddddd0bbb", "db",  &[Register, Immediate]),
+        Opdef::new("sec",    "1001010000001000", "",    &[]),
+        Opdef::new("clc",    "1001010010001000", "",    &[]),
+        Opdef::new("sen",    "1001010000101000", "",    &[]),
+        Opdef::new("cln",    "1001010010101000", "",    &[]),
+        Opdef::new("sez",    "1001010000011000", "",    &[]),
+        Opdef::new("clz",    "1001010010011000", "",    &[]),
+        Opdef::new("sei",    "1001010001111000", "",    &[]),
+        Opdef::new("cli",    "1001010011111000", "",    &[]),
+        Opdef::new("ses",    "1001010001001000", "",    &[]),
+        Opdef::new("cls",    "1001010011001000", "",    &[]),
+        Opdef::new("sev",    "1001010000111000", "",    &[]),
+        Opdef::new("clv",    "1001010010111000", "",    &[]),
+        Opdef::new("set",    "1001010001101000", "",    &[]),
+        Opdef::new("clt",    "1001010011101000", "",    &[]),
+        Opdef::new("seh",    "1001010001011000", "",    &[]),
+        Opdef::new("clh",    "1001010011011000", "",    &[]),
+
+        // mcu control
+        Opdef::new("break",  "1001010110011000", "",    &[]),
+        Opdef::new("nop",    "0000000000000000", "",    &[]),
+        Opdef::new("sleep",  "1001010110001000", "",    &[]),
+        Opdef::new("wdr",    "1001010110101000", "",    &[]),
+    ];
+
+    Isa::new(opdefs)
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::Disassembler;
+    use crate::encode::Bits;
+
+    // the form of name with this pointer operand, or with none
+    fn encode(name: &str, pointer: Option<&str>, args: &[i64]) -> u64 {
+        let isa = instruction_set();
+        let opdef = isa.get(name)
+                       .unwrap()
+                       .iter()
+                       .find(| opdef | {
+                           let form = opdef.args.iter()
+                                                .find_map(| arg | match arg.kind {
+                                                    ArgKind::Pointer(p) => Some(p),
+                                                    _ => None,
+                                                });
+                           form == pointer && opdef.args.len() == args.len()
+                       })
+                       .unwrap();
+        opdef.apply(args).unwrap()
+    }
+
+    #[test]
+    fn datasheet_encodings() {
+        // k is split around the fixed bits of the first word
+        assert_eq!(encode("jmp", None, &[0]), 0x940c_0000);
+        assert_eq!(encode("jmp", None, &[0x12_3456]), 0x949c_3456);
+        assert_eq!(encode("call", None, &[0x1_0000]), 0x940f_0000);
+        assert_eq!(encode("call", None, &[0x3f_ffff]), 0x95ff_ffff);
+
+        // q is scattered over three fields
+        assert_eq!(encode("ldd", Some("Y"), &[24, 0, 63]), 0xad8f);
+        assert_eq!(encode("ldd", Some("Z"), &[0, 0, 1]), 0x8001);
+        assert_eq!(encode("std", Some("Y"), &[0, 63, 24]), 0xaf8f);
+        assert_eq!(encode("std", Some("Z"), &[0, 1, 0]), 0x8201);
+
+        // only the upper register pairs
+        assert_eq!(encode("adiw", None, &[24, 1]), 0x9601);
+        assert_eq!(encode("adiw", None, &[30, 63]), 0x96ff);
+        assert!(instruction_set().get("adiw").unwrap()[0].apply(&[25, 1]).is_err());
+
+        // registers are counted in pairs
+        assert_eq!(encode("movw", None, &[30, 24]), 0x01fc);
+        assert!(instruction_set().get("movw").unwrap()[0].apply(&[31, 24]).is_err());
+
+        // andi with the complement
+        assert_eq!(encode("cbr", None, &[16, 0xff]), 0x7000);
+        assert_eq!(encode("cbr", None, &[16, 0x0f]), 0x7f00);
+        assert!(instruction_set().get("cbr").unwrap()[0].apply(&[16, 0x1ff]).is_err());
+
+        assert_eq!(encode("ldi", None, &[16, 0xff]), 0xef0f);
+        assert_eq!(encode("rjmp", None, &[-1]), 0xcfff);
+    }
+
+    #[test]
+    fn every_opdef_disassembles() {
+        let isa = instruction_set();
+        let settings = compile_settings();
+        let disassembler = Disassembler::new(&isa, &settings);
+        let word_bits = settings.word_bits();
+
+        for opdef in isa.opdefs() {
+            // both ends of every operand, pointers are always 0
+            let ends: Vec<(i64, i64)> = opdef.args.iter()
+                                                 .map(| arg | match arg.kind {
+                                                     ArgKind::Pointer(_) => (0, 0),
+                                                     _ => arg.transform.range(arg.bits()),
+                                                 })
+                                                 .collect();
+            let lows: Vec<i64> = ends.iter().map(| &(min, _) | min).collect();
+            let highs: Vec<i64> = ends.iter().map(| &(_, max) | max).collect();
+
+            for args in &[lows, highs] {
+                let opcode = opdef.apply(args).unwrap();
+                let size = opdef.size(word_bits);
+                let words: Vec<u64> = (0..size).rev()
+                                               .map(| i | (opcode >> (word_bits * i)) & u64::mask(word_bits))
+                                               .collect();
+                let decoded = disassembler.decode(0, &words);
+                let found = decoded.opdef.as_ref().unwrap();
+
+                assert_eq!(decoded.words, words, "{} {:?}", opdef.name, args);
+                // an alias can decode as its base instruction, but has to mean the same opcode
+                assert_eq!(found.apply(&decoded.args).unwrap(), opcode, "{} {:?}", opdef.name, args);
+                assert_eq!(opdef.unapply(opcode).as_ref(), Some(args), "{} {:?}", opdef.name, args);
+                if found.name == opdef.name {
+                    assert_eq!(&decoded.args, args, "{} {:?}", opdef.name, args);
+                }
+            }
+        }
+    }
+}
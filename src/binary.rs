@@ -0,0 +1,17 @@
+//! raw binary images
+
+use crate::image::MemoryImage;
+
+/// one image from the lowest address to the highest, and the address it starts at
+///   bytes between regions are fill
+pub fn flat_image(image: &MemoryImage, fill: u8) -> (u64, Vec<u8>) {
+    let (start, end) = image.span().unwrap_or((0, 0));
+
+    let mut flat = vec![fill; (end - start) as usize];
+    for (addr, bytes) in image.regions() {
+        let at = (addr - start) as usize;
+        flat[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    (start, flat)
+}
@@ -0,0 +1,824 @@
+//! turning code objects into hex records
+
+use std::collections::HashMap;
+
+use crate::binary;
+use crate::device::Device;
+use crate::elf;
+use crate::encode::{Bits, IArg, Instruction, RawData};
+use crate::errors::CompileError;
+use crate::hexfmt::{self, HexFormat, HexObject, HexRecord};
+use crate::image::MemoryImage;
+use crate::isa::ArgKind;
+use crate::reader::Position;
+use crate::srec::{self, SRecord};
+
+/// each section has its own addresses, starting at 0
+///   text counts in the settings address unit, the rest in bytes
+///   output puts them at the same offsets as avr-gcc
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Section {
+    /// code, in the settings address unit
+    Text,
+    /// ram, from sram_start when theres a device
+    Data,
+    /// eeprom
+    Eeprom,
+    /// fuse bytes
+    Fuse,
+}
+
+impl Section {
+    /// in output order
+    pub const ALL: [Section; 4] = [Section::Text, Section::Data, Section::Eeprom, Section::Fuse];
+
+    /// byte offset in output
+    pub fn base(self) -> u64 {
+        match self {
+            Section::Text   => 0,
+            Section::Data   => 0x80_0000,
+            Section::Eeprom => 0x81_0000,
+            Section::Fuse   => 0x82_0000,
+        }
+    }
+
+    /// bytes before the next section in output
+    ///   the last section gets as many as the others
+    pub fn size(self) -> u64 {
+        match self {
+            Section::Text => Section::Data.base(),
+            _             => 0x1_0000,
+        }
+    }
+
+    /// as written in (section name)
+    pub fn name(self) -> &'static str {
+        match self {
+            Section::Text   => "text",
+            Section::Data   => "data",
+            Section::Eeprom => "eeprom",
+            Section::Fuse   => "fuse",
+        }
+    }
+
+    /// inverse of name
+    pub fn from_name(name: &str) -> Option<Self> {
+        Section::ALL.iter()
+                    .copied()
+                    .find(| section | section.name() == name)
+    }
+
+    /// section an output byte address is in
+    pub fn from_addr(addr: u64) -> Self {
+        Section::ALL.iter()
+                    .rev()
+                    .copied()
+                    .find(| section | addr >= section.base())
+                    .unwrap_or(Section::Text)
+    }
+}
+
+/// what a symbol stands for
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SymbolKind {
+    /// a label in a section
+    Address(Section),
+    /// a number from equ or set
+    Constant,
+    /// a register from def
+    Register,
+}
+
+/// a label, constant, or register alias
+///   value is in the section address units for labels
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol {
+    /// what the value means
+    pub kind: SymbolKind,
+    /// the address, constant, or register number
+    pub value: i64,
+    /// set and def can be defined again
+    pub redefinable: bool,
+}
+
+/// symbols by name
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: HashMap<String, Symbol>,
+}
+
+impl SymbolTable {
+    /// SymbolNotFound if its not defined
+    pub fn get(&self, name: &str) -> Result<&Symbol, CompileError> {
+        self.symbols.get(name)
+                    .ok_or_else(|| CompileError::SymbolNotFound(name.to_string()))
+    }
+
+    /// if name is defined
+    pub fn contains(&self, name: &str) -> bool {
+        self.symbols.contains_key(name)
+    }
+
+    /// every symbol, in no order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Symbol)> {
+        self.symbols.iter()
+    }
+
+    /// a symbol can only be redefined if both it and the new one are redefinable
+    ///   and both or neither are registers
+    pub fn define(&mut self, name: &str, symbol: Symbol) -> Result<(), CompileError> {
+        if let Some(prev) = self.symbols.get(name) {
+            let redefinable = prev.redefinable && symbol.redefinable;
+            if !redefinable && matches!(prev.kind, SymbolKind::Address(_)) && matches!(symbol.kind, SymbolKind::Address(_)) {
+                return Err(CompileError::DuplicateLabel(name.to_string()));
+            }
+            let is_register = | kind | kind == SymbolKind::Register;
+            if !redefinable || is_register(prev.kind) != is_register(symbol.kind) {
+                return Err(CompileError::Redefinition {
+                    name: name.to_string(),
+                    kind: symbol.kind,
+                    previous: prev.kind,
+                });
+            }
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+}
+
+/// equ and set define constants, def defines register aliases
+///   set and def can be redefined further on
+///   equ or set of just a label or here defines another address
+///   so the alias is scaled like the label
+#[derive(Debug)]
+pub struct SymbolDef {
+    /// symbol being defined
+    pub name: String,
+    /// Constant for equ and set, Register for def
+    pub kind: SymbolKind,
+    /// resolved where the definition is
+    pub value: IArg,
+    /// set and def
+    pub redefinable: bool,
+}
+
+impl SymbolDef {
+    // addr is where the definition is in section
+    //   other constants are resolved like immediates, so labels in them are bytes
+    pub(crate) fn to_symbol(
+        &self,
+        section: Section,
+        addr: u64,
+        symbols: &SymbolTable,
+        settings: &CompileSettings
+    ) -> Result<Symbol, CompileError> {
+        let (kind, arg_kind) =
+            match self.kind {
+                SymbolKind::Register => (SymbolKind::Register, ArgKind::Register),
+                _ => {
+                    match self.value.address_section(section, symbols) {
+                        Some(section) => (SymbolKind::Address(section), ArgKind::Address),
+                        None => (self.kind, ArgKind::Immediate),
+                    }
+                }
+            };
+
+        Ok(Symbol {
+            kind,
+            value: self.value.resolve(addr, arg_kind, symbols, settings)?,
+            redefinable: self.redefinable,
+        })
+    }
+}
+
+//
+
+/// one top level form, ready to lay out
+#[derive(Debug)]
+pub enum CodeObject {
+    /// one opcode
+    Instruction(Instruction),
+    /// db, dw, dd, ascii, and asciz
+    RawData(RawData),
+    /// org, in the section address units
+    AddressTag(u64),
+    /// a label at the current address
+    LabelTag(String),
+    /// equ, set, and def
+    SymbolTag(SymbolDef),
+    /// objects after this go in the section, where it was last left
+    SectionTag(Section),
+    /// move up to a multiple of this many words
+    Align(u64),
+    /// leave this many words
+    Skip(u64),
+    /// this many words of value
+    Fill {
+        /// in words
+        count: u64,
+        /// written as one word each time
+        value: u64,
+    },
+}
+
+impl CodeObject {
+    /// address after this object, given the address its at
+    pub fn next_address(&self, addr: u64, settings: &CompileSettings) -> u64 {
+        use CodeObject::*;
+
+        match self {
+            AddressTag(tag_addr) => *tag_addr,
+            LabelTag(_)          => addr,
+            SymbolTag(_)         => addr,
+            SectionTag(_)        => addr,
+            Instruction(inst)    => addr + settings.words_to_units(inst.opdef.size(settings.word_bits()) as u64),
+            RawData(data)        => addr + data.size(settings) as u64 / settings.address_unit_size(),
+            Align(words)         => {
+                let units = settings.words_to_units(*words);
+                if units > 0 {
+                    addr.div_ceil(units) * units
+                } else {
+                    addr
+                }
+            }
+            Skip(words)          => addr + settings.words_to_units(*words),
+            Fill { count, .. }   => addr + settings.words_to_units(*count),
+        }
+    }
+}
+
+/// what one step of a code address is
+///   AddressTag, labels, and jumps count in these
+///   hex output is always in bytes
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AddressUnit {
+    /// one address per byte
+    Byte,
+    /// one address per opcode_size bytes
+    Word,
+}
+
+/// order of the bytes within a word
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ByteOrder {
+    /// low byte first
+    Little,
+    /// high byte first
+    Big,
+}
+
+/// how code is laid out and encoded, see avr::compile_settings
+///   fields get added, so start from that or default and change the fields you need
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CompileSettings {
+    /// bytes per word
+    ///   an opcode is one or more words
+    pub opcode_size: u8,
+    /// order of the bytes within a word
+    ///   the words of a multi-word opcode are always most significant first
+    pub byte_order: ByteOrder,
+    /// what labels and jumps count in
+    pub address_unit: AddressUnit,
+    /// bytes in an address
+    ///   every section has to fit, in its own address units
+    pub address_size: u8,
+    /// words in each hex data record
+    pub words_per_record: u8,
+    /// which extended address records hex output can use
+    pub hex_format: HexFormat,
+    /// label written as a start address record
+    pub entry: Option<String>,
+    /// byte between regions in a flat binary
+    pub bin_fill: u8,
+    /// only write this section, moved to address 0
+    ///   None writes them all at their base addresses
+    ///   elf output always has them all
+    pub output_section: Option<Section>,
+    /// e_machine in elf output
+    pub elf_machine: u16,
+    /// e_flags in elf output
+    pub elf_flags: u32,
+    /// word written over space left by Align and Skip
+    ///   None leaves a gap in the output
+    pub gap_fill: Option<u64>,
+    /// chip the output has to fit in
+    ///   None checks nothing
+    pub device: Option<Device>,
+}
+
+/// byte words and byte addresses, and nothing written that wasnt asked for
+impl Default for CompileSettings {
+    fn default() -> Self {
+        CompileSettings {
+            opcode_size: 1,
+            byte_order: ByteOrder::Big,
+            address_unit: AddressUnit::Byte,
+            address_size: 2,
+            words_per_record: 16,
+            hex_format: HexFormat::I32,
+            entry: None,
+            bin_fill: 0,
+            output_section: None,
+            // EM_NONE
+            elf_machine: 0,
+            elf_flags: 0,
+            gap_fill: None,
+            device: None,
+        }
+    }
+}
+
+impl CompileSettings {
+    /// bits per word
+    pub fn word_bits(&self) -> usize {
+        self.opcode_size as usize * 8
+    }
+
+    /// settings for code in section
+    pub fn for_section(&self, section: Section) -> CompileSettings {
+        let mut settings = self.clone();
+        if section != Section::Text {
+            settings.address_unit = AddressUnit::Byte;
+        }
+        settings
+    }
+
+    /// bytes per address unit
+    pub fn address_unit_size(&self) -> u64 {
+        match self.address_unit {
+            AddressUnit::Byte => 1,
+            AddressUnit::Word => self.opcode_size as u64,
+        }
+    }
+
+    /// where a section starts before any org
+    ///   data starts in sram when theres a device
+    pub fn section_start(&self, section: Section) -> u64 {
+        self.device.as_ref()
+                   .and_then(| device | device.memory(section))
+                   .map(| (start, _) | start)
+                   .unwrap_or(0)
+    }
+
+    /// words in the section address units
+    pub fn words_to_units(&self, words: u64) -> u64 {
+        words * self.opcode_size as u64 / self.address_unit_size()
+    }
+
+    /// an opcode size words long as bytes, most significant word first
+    pub fn opcode_bytes(&self, value: u64, size: usize) -> Vec<u8> {
+        (0..size).rev()
+                 .flat_map(| i | {
+                     let word = (value >> (i * self.word_bits())) & u64::mask(self.word_bits());
+                     let mut bytes = word.to_bytes(self.opcode_size as usize);
+                     if self.byte_order == ByteOrder::Little {
+                         bytes.reverse();
+                     }
+                     bytes
+                 })
+                 .collect()
+    }
+
+    /// one word from its bytes, in the settings byte order
+    pub fn word_from_bytes(&self, bytes: &[u8]) -> u64 {
+        let fold = | acc, &byte | (acc << 8) | byte as u64;
+        match self.byte_order {
+            ByteOrder::Little => bytes.iter().rev().fold(0, fold),
+            ByteOrder::Big    => bytes.iter().fold(0, fold),
+        }
+    }
+}
+
+//
+
+// where each object is, in its section
+fn generate_address_image(code: &[(Position, CodeObject)], settings: &CompileSettings) -> Result<Vec<(Section, u64)>, CompileError> {
+    use CodeObject::*;
+
+    let mut offset =
+        match code.first() {
+            Some((_, AddressTag(addr))) => *addr,
+            Some((pos, _)) => return Err(CompileError::StartWithAddressTag.at(*pos)),
+            None => return Err(CompileError::EmptyProgram),
+        };
+    let mut section = Section::Text;
+    let mut section_settings = settings.clone();
+    let mut offsets = HashMap::new();
+
+    // last address in the section, in its address units
+    //   it has to fit in an address, and not run into the next section
+    let address_max = u64::mask(settings.address_size as usize * 8);
+    let section_max = | section: Section, settings: &CompileSettings | {
+        address_max.min(section.size() / settings.address_unit_size() - 1)
+    };
+    let mut max = section_max(section, settings);
+
+    let mut addr_image = Vec::with_capacity(code.len());
+
+    for (pos, obj) in code.iter() {
+        addr_image.push((section, offset));
+        if let SectionTag(next) = obj {
+            offsets.insert(section, offset);
+            section = *next;
+            section_settings = settings.for_section(section);
+            max = section_max(section, &section_settings);
+            offset = offsets.get(&section).copied().unwrap_or_else(|| settings.section_start(section));
+        } else {
+            let addr = offset;
+            offset = obj.next_address(addr, &section_settings);
+            // one past the last address is fine, for a label at the end
+            if offset > 0 && offset - 1 > max {
+                return Err(CompileError::AddressOverflow {
+                    section,
+                    addr,
+                    label: label_near(code, &addr_image, section, addr),
+                    end: offset,
+                    max,
+                }.at(*pos));
+            }
+        }
+    }
+
+    check_overlaps(code, &addr_image, settings)?;
+
+    Ok(addr_image)
+}
+
+// the last label at or before addr in section
+fn label_near(code: &[(Position, CodeObject)], address_image: &[(Section, u64)], section: Section, addr: u64) -> Option<String> {
+    code.iter()
+        .zip(address_image.iter())
+        .filter_map(| ((_, obj), &(label_section, label_addr)) | {
+            match obj {
+                CodeObject::LabelTag(label) if label_section == section && label_addr <= addr => Some((label_addr, label)),
+                _ => None,
+            }
+        })
+        .max_by_key(| &(label_addr, _) | label_addr)
+        .map(| (_, label) | label.clone())
+}
+
+// Align and Skip only take space when theres a gap_fill
+fn takes_space(obj: &CodeObject, settings: &CompileSettings) -> bool {
+    use CodeObject::*;
+
+    match obj {
+        Instruction(_) | RawData(_) | Fill { .. } => true,
+        Align(_) | Skip(_)                          => settings.gap_fill.is_some(),
+        _ => false,
+    }
+}
+
+// an AddressTag can move back over code thats already placed
+fn check_overlaps(code: &[(Position, CodeObject)], address_image: &[(Section, u64)], settings: &CompileSettings) -> Result<(), CompileError> {
+    let mut spans: Vec<(Section, u64, u64, usize)> = Vec::new();
+    for (i, ((_, obj), &(section, addr))) in code.iter().zip(address_image.iter()).enumerate() {
+        let end = obj.next_address(addr, &settings.for_section(section));
+        if takes_space(obj, settings) && end > addr {
+            spans.push((section, addr, end, i));
+        }
+    }
+    spans.sort_by_key(| &(section, addr, _, i) | (Section::ALL.iter().position(| &s | s == section), addr, i));
+
+    // spans dont overlap until one is found, so the last one reaches furthest
+    let mut last: Option<(Section, u64, u64, usize)> = None;
+    for span in spans {
+        let (section, addr, _, i) = span;
+        match last {
+            Some((prev_section, prev_addr, prev_end, prev_i)) if prev_section == section && addr < prev_end => {
+                // the one later in the code is the one that overlaps
+                let ((addr, i), (previous, previous_i)) =
+                    if i > prev_i {
+                        ((addr, i), (prev_addr, prev_i))
+                    } else {
+                        ((prev_addr, prev_i), (addr, i))
+                    };
+                return Err(CompileError::OverlappingCode {
+                    section,
+                    addr,
+                    label: label_near(&code[..=i], address_image, section, addr),
+                    previous,
+                    previous_label: label_near(&code[..=previous_i], address_image, section, previous),
+                }.at(code[i].0));
+            }
+            _ => last = Some(span),
+        }
+    }
+
+    Ok(())
+}
+
+// labels are all defined first so constants can use them
+//   a redefinable symbol keeps its first value here
+//   generate_hex_objects picks up later values in order
+fn generate_symbol_table(
+    code: &[(Position, CodeObject)],
+    address_image: &[(Section, u64)],
+    settings: &CompileSettings
+) -> Result<SymbolTable, CompileError> {
+    use CodeObject::*;
+
+    let mut symbols = SymbolTable::default();
+
+    for ((pos, obj), &(section, addr)) in code.iter().zip(address_image.iter()) {
+        if let LabelTag(label) = obj {
+            symbols.define(label, Symbol {
+                kind: SymbolKind::Address(section),
+                value: addr as i64,
+                redefinable: false,
+            }).map_err(| err | err.at(*pos))?;
+        }
+    }
+
+    for ((pos, obj), &(section, addr)) in code.iter().zip(address_image.iter()) {
+        if let SymbolTag(def) = obj {
+            let symbol = def.to_symbol(section, addr, &symbols, &settings.for_section(section))
+                            .map_err(| err | err.at(*pos))?;
+            let is_redefinition = symbols.contains(&def.name);
+            let defined =
+                if !is_redefinition || !symbol.redefinable {
+                    symbols.define(&def.name, symbol)
+                } else {
+                    // checks the rules without changing the value
+                    symbols.clone().define(&def.name, symbol)
+                };
+            defined.map_err(| err | err.at(*pos))?;
+        }
+    }
+
+    Ok(symbols)
+}
+
+// byte address in output
+fn output_address(section: Section, addr: u64, settings: &CompileSettings) -> u64 {
+    section.base() + addr * settings.address_unit_size()
+}
+
+// skipped space is filled with settings.gap_fill if its set
+//   otherwise output starts again at the end of the gap
+fn gap(section: Section, addr: u64, next_addr: u64, settings: &CompileSettings) -> HexObject {
+    let bytes = ((next_addr - addr) * settings.address_unit_size()) as usize;
+    match settings.gap_fill {
+        _ if bytes == 0 => HexObject::Data(Vec::new()),
+        Some(value) => {
+            let mut data = settings.opcode_bytes(value, 1).repeat(bytes.div_ceil(settings.opcode_size as usize));
+            data.truncate(bytes);
+            HexObject::Data(data)
+        }
+        None => HexObject::AddressTag(output_address(section, next_addr, settings)),
+    }
+}
+
+// None for objects that only change symbols
+fn hex_object(
+    obj: &CodeObject,
+    section: Section,
+    addr: u64,
+    symbols: &mut SymbolTable,
+    settings: &CompileSettings
+) -> Result<Option<HexObject>, CompileError> {
+    use CodeObject::*;
+
+    let hex_object =
+        match obj {
+            AddressTag(tag_addr) => HexObject::AddressTag(output_address(section, *tag_addr, settings)),
+            LabelTag(_)          => return Ok(None),
+            SectionTag(_)        => return Ok(None),
+            SymbolTag(def)       => {
+                if def.redefinable {
+                    let symbol = def.to_symbol(section, addr, symbols, settings)?;
+                    symbols.define(&def.name, symbol)?;
+                }
+                return Ok(None);
+            }
+            Instruction(inst)    => {
+                if let Some(device) = &settings.device {
+                    if !device.core.has(&inst.opdef.name) {
+                        return Err(CompileError::MissingInstruction {
+                            instruction: inst.opdef.name.clone(),
+                            device: device.name,
+                            core: device.core.name(),
+                        });
+                    }
+                }
+                let args = inst.resolve_args(addr, symbols, settings)?;
+                HexObject::Opcode {
+                    value: inst.opdef.apply(&args)?,
+                    size: inst.opdef.size(settings.word_bits()),
+                }
+            }
+            RawData(data)        => HexObject::Data(data.to_bytes(addr, symbols, settings)?),
+            Align(_) | Skip(_)   => gap(section, addr, obj.next_address(addr, settings), settings),
+            Fill { count, value } => {
+                if *value > u64::mask(settings.word_bits()) {
+                    return Err(CompileError::DataOutOfRange {
+                        addr,
+                        value: *value as i64,
+                        min: 0,
+                        max: u64::mask(settings.word_bits()) as i64,
+                    });
+                }
+                HexObject::Data(settings.opcode_bytes(*value, 1).repeat(*count as usize))
+            }
+        };
+    Ok(Some(hex_object))
+}
+
+// errors are at the position of the form that caused them
+fn generate_hex_objects(
+    code: &[(Position, CodeObject)],
+    address_image: &[(Section, u64)],
+    symbols: &SymbolTable,
+    settings: &CompileSettings
+) -> Result<Vec<HexObject>, CompileError> {
+    let mut symbols = symbols.clone();
+    let mut hex_objects = Vec::with_capacity(code.len());
+    let mut current = Section::Text;
+    let mut section_settings = settings.clone();
+
+    for ((pos, obj), &(section, addr)) in code.iter().zip(address_image.iter()) {
+        // picks up where the section was left
+        if section != current {
+            current = section;
+            section_settings = settings.for_section(section);
+            hex_objects.push(HexObject::AddressTag(output_address(section, addr, &section_settings)));
+        }
+
+        let hex_object = hex_object(obj, section, addr, &mut symbols, &section_settings)
+                                   .map_err(| err | err.at(*pos))?;
+        hex_objects.extend(hex_object);
+    }
+
+    Ok(hex_objects)
+}
+
+// the image for the hex, srec, and bin writers
+//   only settings.output_section, if its set
+fn output_image(image: &MemoryImage, settings: &CompileSettings) -> MemoryImage {
+    match settings.output_section {
+        Some(section) => image.section(section),
+        None => image.clone(),
+    }
+}
+
+/// output as a memory image, as the hex, srec, and bin writers see it
+pub fn compile_image(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<MemoryImage, CompileError> {
+    let (image, _, _) = layout(settings, code)?;
+    Ok(output_image(&image, settings))
+}
+
+/// output as one image and the byte address it starts at
+///   gaps are settings.bin_fill
+pub fn compile_bin(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<(u64, Vec<u8>), CompileError> {
+    let image = compile_image(settings, code)?;
+    Ok(binary::flat_image(&image, settings.bin_fill))
+}
+
+/// an elf executable with every section, and labels and constants as symbols
+///   with the image compile_image would give, so listings dont lay out again
+pub fn compile_elf(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<(Vec<u8>, MemoryImage), CompileError> {
+    let (image, symbols, entry) = layout(settings, code)?;
+    let elf = elf::generate_elf(&image, &symbols, entry, settings)?;
+    Ok((elf, output_image(&image, settings)))
+}
+
+// every section at its base address, the symbols, and the entry in bytes
+fn layout(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<(MemoryImage, SymbolTable, Option<u64>), CompileError> {
+    let address_image = generate_address_image(code, settings)?;
+    let symbols = generate_symbol_table(code, &address_image, settings)?;
+    let hex_objects = generate_hex_objects(code, &address_image, &symbols, settings)?;
+    let image = MemoryImage::from_hex_objects(&hex_objects, settings)?;
+    if let Some(device) = &settings.device {
+        device.check(&image)
+              .map_err(| err | at_byte(err, code, &address_image, settings))?;
+    }
+    let entry = entry(&symbols, settings)?;
+    Ok((image, symbols, entry))
+}
+
+// puts a DoesntFit at the form that wrote the byte
+fn at_byte(err: CompileError, code: &[(Position, CodeObject)], address_image: &[(Section, u64)], settings: &CompileSettings) -> CompileError {
+    let (section, byte) =
+        match err {
+            CompileError::DoesntFit { section, addr, .. } => (section, addr),
+            _ => return err,
+        };
+    let pos = code.iter()
+                  .zip(address_image.iter())
+                  .find(| ((_, obj), &(obj_section, addr)) | {
+                      let section_settings = settings.for_section(obj_section);
+                      let unit = section_settings.address_unit_size();
+                      let end = obj.next_address(addr, &section_settings);
+                      obj_section == section && takes_space(obj, settings) && addr * unit <= byte && byte < end * unit
+                  })
+                  .map(| ((pos, _), _) | *pos);
+    match pos {
+        Some(pos) => err.at(pos),
+        None => err,
+    }
+}
+
+fn entry(symbols: &SymbolTable, settings: &CompileSettings) -> Result<Option<u64>, CompileError> {
+    settings.entry.as_ref()
+                  .map(| label | entry_address(label, symbols, settings))
+                  .transpose()
+}
+
+// in bytes
+fn entry_address(label: &str, symbols: &SymbolTable, settings: &CompileSettings) -> Result<u64, CompileError> {
+    let symbol = symbols.get(label)?;
+    if symbol.kind != SymbolKind::Address(Section::Text) {
+        return Err(CompileError::WrongSymbolKind(label.to_string(), symbol.kind));
+    }
+    Ok(symbol.value as u64 * settings.address_unit_size())
+}
+
+/// symbols as the start of the code sees them, for maps and listings
+pub fn symbol_table(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<SymbolTable, CompileError> {
+    let address_image = generate_address_image(code, settings)?;
+    generate_symbol_table(code, &address_image, settings)
+}
+
+/// lays out code from its first AddressTag and encodes it
+///   the records end with a start record if settings.entry is set, then end of file
+pub fn compile(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<Vec<HexRecord>, CompileError> {
+    let (image, _, entry) = layout(settings, code)?;
+    hexfmt::generate_hex_records(&output_image(&image, settings), entry, settings)
+}
+
+/// like compile, but to s-records
+///   the address width is the narrowest that fits
+pub fn compile_srec(settings: &CompileSettings, code: &[(Position, CodeObject)]) -> Result<Vec<SRecord>, CompileError> {
+    let (image, _, entry) = layout(settings, code)?;
+    srec::generate_srecords(&output_image(&image, settings), entry, settings)
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+    use crate::device;
+    use crate::frontend;
+
+    fn layout_error(settings: &CompileSettings, source: &str) -> CompileError {
+        let code = frontend::read_program(source, &avr::instruction_set()).unwrap();
+        match compile_image(settings, &code).unwrap_err() {
+            CompileError::At(_, err) => *err,
+            err => err,
+        }
+    }
+
+    #[test]
+    fn sections_stop_at_the_next_one() {
+        // three byte addresses, but data is only 64K before eeprom
+        let settings = device::find("atmega2560").unwrap().compile_settings();
+        let err = layout_error(&settings, "(org 0) (section data) (org #x10000) (db 1 2)");
+        assert!(matches!(err, CompileError::AddressOverflow { section: Section::Data, max: 0xffff, .. }));
+
+        // text stops where data starts in output
+        let mut settings = avr::compile_settings();
+        settings.address_size = 4;
+        let err = layout_error(&settings, "(org #x400000) (nop)");
+        assert!(matches!(err, CompileError::AddressOverflow { section: Section::Text, max: 0x3f_ffff, .. }));
+    }
+    fn assembles(settings: &CompileSettings, source: &str) -> bool {
+        let code = frontend::read_program(source, &avr::instruction_set()).unwrap();
+        compile_image(settings, &code).is_ok()
+    }
+
+    #[test]
+    fn org_back_over_code() {
+        let source = "(org 0) (label start) (nop) (nop) (label back)\n(org 1) (label again) (nop)";
+        let code = frontend::read_program(source, &avr::instruction_set()).unwrap();
+        let err = compile_image(&avr::compile_settings(), &code).unwrap_err();
+        assert_eq!(err.to_string(), "2:23: text at 0x1 (near 'again') overlaps code already at 0x1 (near 'start')");
+
+        // labels and orgs dont take space
+        assert!(assembles(&avr::compile_settings(), "(org 4) (nop) (label end) (org 0) (label start) (org 4) (label again)"));
+    }
+
+    #[test]
+    fn gaps_only_overlap_when_filled() {
+        let mut settings = avr::compile_settings();
+        for source in &["(org 0) (nop) (skip 2) (org 2) (nop)", "(org 0) (nop) (align 4) (org 3) (nop)"] {
+            settings.gap_fill = None;
+            assert!(assembles(&settings, source), "{}", source);
+            settings.gap_fill = Some(0xffff);
+            let err = layout_error(&settings, source);
+            assert!(matches!(err, CompileError::OverlappingCode { section: Section::Text, previous: 1, .. }), "{}", source);
+        }
+    }
+
+    #[test]
+    fn one_past_the_address_size() {
+        let mut settings = avr::compile_settings();
+        settings.address_size = 1;
+        // a label can be one past the end, code cant
+        assert!(assembles(&settings, "(org #xff) (nop) (label end)"));
+        let err = layout_error(&settings, "(org #xff) (label last) (nop) (nop)");
+        assert!(matches!(&err, CompileError::AddressOverflow { section: Section::Text, addr: 0x100, end: 0x101, max: 0xff, label: Some(label) } if label == "last"));
+    }
+}
@@ -0,0 +1,302 @@
+//! avr chips, and checking that code fits in them
+
+use crate::avr;
+use crate::compile::{CompileSettings, Section};
+use crate::errors::CompileError;
+use crate::image::MemoryImage;
+
+/// what avr-gcc calls the architecture
+///   it picks the elf flags and which instructions assemble
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Core {
+    /// no mul, jmp, or call, like the attiny85
+    Avr25,
+    /// mul, but no jmp or call, like the atmega8
+    Avr4,
+    /// jmp and call, up to 128K flash
+    Avr5,
+    /// eijmp, eicall, and elpm, past 128K flash
+    Avr6,
+    /// xmegas up to 64K flash, with des
+    XMega2,
+}
+
+impl Core {
+    /// as avr-gcc names it
+    pub fn name(self) -> &'static str {
+        match self {
+            Core::Avr25  => "avr25",
+            Core::Avr4   => "avr4",
+            Core::Avr5   => "avr5",
+            Core::Avr6   => "avr6",
+            Core::XMega2 => "avrxmega2",
+        }
+    }
+
+    /// from the instruction set manual
+    ///   xch, las, lac, and lat are only on xmegas with usb, which arent here
+    pub fn has(self, instruction: &str) -> bool {
+        let multiply = matches!(instruction, "mul" | "muls" | "mulsu" | "fmul" | "fmuls" | "fmulsu");
+        let long_jump = matches!(instruction, "jmp" | "call");
+        let extended = matches!(instruction, "eijmp" | "eicall" | "elpm");
+        let des = instruction == "des";
+        let read_modify_write = matches!(instruction, "xch" | "las" | "lac" | "lat");
+
+        match self {
+            Core::Avr25  => !(multiply || long_jump || extended || des || read_modify_write),
+            Core::Avr4   => !(long_jump || extended || des || read_modify_write),
+            Core::Avr5   => !(extended || des || read_modify_write),
+            Core::Avr6   => !(des || read_modify_write),
+            Core::XMega2 => !(extended || read_modify_write),
+        }
+    }
+
+    /// e_flags for elf output
+    pub fn elf_flags(self) -> u32 {
+        match self {
+            Core::Avr25  => 25,
+            Core::Avr4   => 4,
+            Core::Avr5   => 5,
+            Core::Avr6   => 6,
+            Core::XMega2 => 102,
+        }
+    }
+}
+
+/// sizes are in bytes
+///   output is checked against the memory sizes and the core
+///   page_size, boot_sizes, and vectors are only for tools that use the profile, nothing checks them
+#[derive(Clone, Debug)]
+pub struct Device {
+    /// lowercase, like atmega328p
+    pub name: &'static str,
+    /// picks the instructions and the elf flags
+    pub core: Core,
+    /// with the boot section, if theres one
+    pub flash_size: u64,
+    /// data addresses below this are registers and io
+    pub sram_start: u64,
+    /// internal sram, from sram_start
+    pub sram_size: u64,
+    /// zero if theres no eeprom
+    pub eeprom_size: u64,
+    /// flash page, for self programming
+    pub page_size: u64,
+    /// the sizes the boot fuses can pick, smallest first
+    ///   empty if theres no boot section
+    pub boot_sizes: &'static [u64],
+    /// including reset
+    pub vectors: u16,
+}
+
+/// from the datasheets
+pub const DEVICES: &[Device] = &[
+    Device {
+        name: "attiny13",
+        core: Core::Avr25,
+        flash_size: 1024,
+        sram_start: 0x60,
+        sram_size: 64,
+        eeprom_size: 64,
+        page_size: 32,
+        boot_sizes: &[],
+        vectors: 10,
+    },
+    Device {
+        name: "attiny85",
+        core: Core::Avr25,
+        flash_size: 8192,
+        sram_start: 0x60,
+        sram_size: 512,
+        eeprom_size: 512,
+        page_size: 64,
+        boot_sizes: &[],
+        vectors: 15,
+    },
+    Device {
+        name: "atmega8",
+        core: Core::Avr4,
+        flash_size: 8192,
+        sram_start: 0x60,
+        sram_size: 1024,
+        eeprom_size: 512,
+        page_size: 64,
+        boot_sizes: &[256, 512, 1024, 2048],
+        vectors: 19,
+    },
+    Device {
+        name: "atmega168",
+        core: Core::Avr5,
+        flash_size: 16384,
+        sram_start: 0x100,
+        sram_size: 1024,
+        eeprom_size: 512,
+        page_size: 128,
+        boot_sizes: &[256, 512, 1024, 2048],
+        vectors: 26,
+    },
+    Device {
+        name: "atmega328p",
+        core: Core::Avr5,
+        flash_size: 32768,
+        sram_start: 0x100,
+        sram_size: 2048,
+        eeprom_size: 1024,
+        page_size: 128,
+        boot_sizes: &[512, 1024, 2048, 4096],
+        vectors: 26,
+    },
+    Device {
+        name: "atmega32u4",
+        core: Core::Avr5,
+        flash_size: 32768,
+        sram_start: 0x100,
+        sram_size: 2560,
+        eeprom_size: 1024,
+        page_size: 128,
+        boot_sizes: &[512, 1024, 2048, 4096],
+        vectors: 43,
+    },
+    Device {
+        name: "atmega2560",
+        core: Core::Avr6,
+        flash_size: 262144,
+        sram_start: 0x200,
+        sram_size: 8192,
+        eeprom_size: 4096,
+        page_size: 256,
+        boot_sizes: &[1024, 2048, 4096, 8192],
+        vectors: 57,
+    },
+    Device {
+        name: "atxmega32a4",
+        core: Core::XMega2,
+        flash_size: 36864,
+        sram_start: 0x2000,
+        sram_size: 4096,
+        eeprom_size: 1024,
+        page_size: 256,
+        boot_sizes: &[4096],
+        vectors: 94,
+    },
+];
+
+/// a profile by its lowercase name, like atmega328p
+pub fn find(name: &str) -> Option<&'static Device> {
+    DEVICES.iter()
+           .find(| device | device.name == name)
+}
+
+impl Device {
+    /// avr settings, with an address size that reaches all of flash
+    ///   the data section starts at sram_start
+    pub fn compile_settings(&self) -> CompileSettings {
+        let mut settings = avr::compile_settings();
+        let flash_words = self.flash_size / settings.opcode_size as u64;
+        if flash_words > 0x1_0000 {
+            settings.address_size = 3;
+        }
+        settings.elf_flags = self.core.elf_flags();
+        settings.device = Some(self.clone());
+        settings
+    }
+
+    /// start and end of a section, in its own byte addresses
+    ///   fuses arent checked
+    pub fn memory(&self, section: Section) -> Option<(u64, u64)> {
+        match section {
+            Section::Text   => Some((0, self.flash_size)),
+            Section::Data   => Some((self.sram_start, self.sram_start + self.sram_size)),
+            Section::Eeprom => Some((0, self.eeprom_size)),
+            Section::Fuse   => None,
+        }
+    }
+
+    /// image has every section at its base address
+    pub fn check(&self, image: &MemoryImage) -> Result<(), CompileError> {
+        for &section in &Section::ALL {
+            let (start, end) =
+                match self.memory(section) {
+                    Some(range) => range,
+                    None => continue,
+                };
+            let outside = image.section(section)
+                               .regions()
+                               .find_map(| (addr, bytes) | {
+                                   if addr < start {
+                                       Some(addr)
+                                   } else if addr + bytes.len() as u64 > end {
+                                       Some(addr.max(end))
+                                   } else {
+                                       None
+                                   }
+                               });
+            if let Some(addr) = outside {
+                return Err(CompileError::DoesntFit {
+                    device: self.name,
+                    section,
+                    addr,
+                    start,
+                    end,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frontend;
+
+    fn compile_for(device: &str, source: &str) -> Result<MemoryImage, CompileError> {
+        let device = find(device).unwrap();
+        let code = frontend::read_program(source, &avr::instruction_set()).unwrap();
+        crate::compile_image(&device.compile_settings(), &code)
+    }
+
+    #[test]
+    fn cores_lack_instructions() {
+        assert!(!Core::Avr25.has("jmp"));
+        assert!(!Core::Avr25.has("mul"));
+        assert!(Core::Avr4.has("mul"));
+        assert!(!Core::Avr4.has("call"));
+        assert!(Core::Avr5.has("call"));
+        assert!(!Core::Avr5.has("elpm"));
+        assert!(Core::Avr6.has("elpm"));
+        assert!(!Core::Avr6.has("des"));
+        assert!(Core::XMega2.has("des"));
+        assert!(!Core::XMega2.has("eicall"));
+        assert!(Core::Avr25.has("rjmp"));
+
+        let err = compile_for("attiny85", "(org 0) (nop)\n(jmp 0)").unwrap_err();
+        assert_eq!(err.to_string(), "2:1: attiny85 doesnt have jmp, its core is avr25");
+        let err = compile_for("atmega328p", "(org 0) (elpm)").unwrap_err();
+        assert_eq!(err.to_string(), "1:9: atmega328p doesnt have elpm, its core is avr5");
+        assert!(compile_for("atmega2560", "(org 0) (elpm)").is_ok());
+    }
+
+    #[test]
+    fn output_has_to_fit() {
+        // flash is 512 words
+        assert!(compile_for("attiny13", "(org #x1ff) (nop)").is_ok());
+        let err = compile_for("attiny13", "(org #x1fe) (nop)\n(db 1 2 3)").unwrap_err();
+        assert_eq!(err.to_string(), "2:1: text byte at 0x400 doesnt fit in attiny13 flash, which is 0x0..0x400");
+
+        // sram is 0x60..0x260
+        assert!(compile_for("attiny85", "(org 0) (section data) (db 1) (org #x25f) (db 2)").is_ok());
+        let err = compile_for("attiny85", "(org 0) (section data) (org #x20) (db 1)").unwrap_err();
+        assert!(matches!(err, CompileError::At(_, err) if matches!(*err, CompileError::DoesntFit { section: Section::Data, addr: 0x20, start: 0x60, .. })));
+        let err = compile_for("attiny85", "(org 0) (section data) (org #x25f) (db 1 2)").unwrap_err();
+        assert!(matches!(err, CompileError::At(_, err) if matches!(*err, CompileError::DoesntFit { section: Section::Data, addr: 0x260, end: 0x260, .. })));
+
+        let err = compile_for("atmega8", "(org 0) (section eeprom) (org 511) (dw 1)").unwrap_err();
+        assert!(matches!(err, CompileError::At(_, err) if matches!(*err, CompileError::DoesntFit { section: Section::Eeprom, addr: 0x200, .. })));
+
+        // fuses arent checked
+        assert!(compile_for("attiny13", "(org 0) (section fuse) (org 100) (db 1)").is_ok());
+    }
+}
@@ -0,0 +1,166 @@
+//! turning opcodes back into instructions
+
+use std::fmt;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use crate::compile::CompileSettings;
+use crate::hexfmt::{hex_regions, HexRecord};
+use crate::isa::{ArgKind, Isa, Opdef};
+
+/// one decoded instruction, or one word that isnt
+#[derive(Debug)]
+pub struct Disassembled {
+    /// in address units
+    pub addr: u64,
+    // relative operands count from here
+    next_word: u64,
+    /// opcode words, most significant first
+    pub words: Vec<u64>,
+    /// None if no opdef matched, words then holds the one unknown word
+    pub opdef: Option<Arc<Opdef>>,
+    /// decoded operands, relative ones as offsets
+    pub args: Vec<i64>,
+}
+
+impl Display for Disassembled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words: Vec<String> = self.words.iter()
+                                           .map(| word | format!("{:04x}", word))
+                                           .collect();
+        write!(f, "{:06x}:  {:<10}  ", self.addr, words.join(" "))?;
+
+        let opdef =
+            if let Some(opdef) = &self.opdef {
+                opdef
+            } else {
+                return write!(f, "; unknown #x{:04x}", self.words[0]);
+            };
+
+        write!(f, "({}", opdef.name)?;
+        let mut offsets = Vec::new();
+        for (arg, &val) in opdef.args.iter().zip(self.args.iter()) {
+            match arg.kind {
+                ArgKind::Register   => write!(f, " r{}", val)?,
+                ArgKind::Immediate  => write!(f, " {}", val)?,
+                ArgKind::Address    => write!(f, " #x{:x}", val)?,
+                ArgKind::Absolute   => write!(f, " #x{:x}", val)?,
+                ArgKind::Relative   => {
+                    write!(f, " #x{:x}", self.next_word as i64 + val)?;
+                    offsets.push(val);
+                }
+                ArgKind::Pointer(name) => write!(f, " {}", name)?,
+            }
+        }
+        write!(f, ")")?;
+
+        for offset in offsets {
+            write!(f, " ; {:+}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// decodes opcodes with the opdefs of an isa
+pub struct Disassembler {
+    // most specific first
+    opdefs: Vec<Arc<Opdef>>,
+    settings: CompileSettings,
+}
+
+impl Disassembler {
+    /// opdefs are tried most specific first, so aliases lose to their base instruction
+    pub fn new(isa: &Isa, settings: &CompileSettings) -> Self {
+        let mut opdefs: Vec<Arc<Opdef>> = isa.opdefs()
+                                             .cloned()
+                                             .collect();
+
+        // aliases share encodings with their base instruction
+        //   prefer more fixed bits, then more operands, then the name
+        //   so sec wins over bset, eor over clr, and andi over cbr
+        opdefs.sort_by(| a, b | {
+            b.fixed_mask().count_ones().cmp(&a.fixed_mask().count_ones())
+             .then(b.args.len().cmp(&a.args.len()))
+             .then(a.name.cmp(&b.name))
+        });
+
+        Disassembler {
+            opdefs,
+            settings: settings.clone(),
+        }
+    }
+
+    /// the instruction at the start of words, addr is in address units
+    pub fn decode(&self, addr: u64, words: &[u64]) -> Disassembled {
+        let word_bits = self.settings.word_bits();
+        let next_word = addr + self.settings.words_to_units(1);
+
+        for opdef in &self.opdefs {
+            let size = opdef.size(word_bits);
+            if size > words.len() {
+                continue;
+            }
+
+            let opcode = words[..size].iter()
+                                      .fold(0, | acc, &word | (acc << word_bits) | word);
+            if let Some(args) = opdef.unapply(opcode) {
+                return Disassembled {
+                    addr,
+                    next_word,
+                    words: words[..size].to_vec(),
+                    opdef: Some(opdef.clone()),
+                    args,
+                };
+            }
+        }
+
+        Disassembled {
+            addr,
+            next_word,
+            words: words[..1].to_vec(),
+            opdef: None,
+            args: Vec::new(),
+        }
+    }
+
+    /// every instruction in words, addr is where the first one is
+    pub fn disassemble(&self, addr: u64, words: &[u64]) -> Vec<Disassembled> {
+        let mut ret = Vec::new();
+        let mut at = 0;
+        while at < words.len() {
+            let line = self.decode(addr + self.settings.words_to_units(at as u64), &words[at..]);
+            at += line.words.len();
+            ret.push(line);
+        }
+        ret
+    }
+}
+
+/// regions are at byte addresses, like from hex_regions
+pub fn disassemble_regions(
+    isa: &Isa,
+    regions: &[(u64, Vec<u8>)],
+    settings: &CompileSettings
+) -> Vec<Disassembled> {
+    let disassembler = Disassembler::new(isa, settings);
+    let word_size = settings.opcode_size as usize;
+
+    regions
+        .iter()
+        .flat_map(| (byte_addr, bytes) | {
+            let words: Vec<u64> = bytes.chunks(word_size)
+                                       .map(| chunk | settings.word_from_bytes(chunk))
+                                       .collect();
+            disassembler.disassemble(byte_addr / settings.address_unit_size(), &words)
+        })
+        .collect()
+}
+
+/// every data record, decoded as code
+pub fn disassemble_hex(
+    isa: &Isa,
+    records: &[HexRecord],
+    settings: &CompileSettings
+) -> Vec<Disassembled> {
+    disassemble_regions(isa, &hex_regions(records), settings)
+}
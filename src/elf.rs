@@ -0,0 +1,263 @@
+//! elf32 executables, laid out like avr-gcc output
+
+use std::convert::TryFrom;
+
+use crate::binary;
+use crate::compile::{CompileSettings, Section, SymbolKind, SymbolTable};
+use crate::errors::CompileError;
+use crate::image::MemoryImage;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+const SYM_SIZE: usize = 16;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+const SHN_ABS: u16 = 0xfff1;
+
+const STB_GLOBAL: u8 = 1;
+const STT_NOTYPE: u8 = 0;
+
+fn put_u16(buf: &mut Vec<u8>, val: u16) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_le_bytes());
+}
+
+fn to_u32(addr: u64) -> Result<u32, CompileError> {
+    u32::try_from(addr).map_err(|_| CompileError::ElfAddressOutOfRange(addr))
+}
+
+// names go in one after another, each ending in a zero
+//   offset 0 is the empty name
+struct StringTable {
+    bytes: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        StringTable { bytes: vec![0] }
+    }
+
+    fn add(&mut self, name: &str) -> u32 {
+        let at = self.bytes.len() as u32;
+        self.bytes.extend_from_slice(name.as_bytes());
+        self.bytes.push(0);
+        at
+    }
+}
+
+struct SectionHeader {
+    name: u32,
+    typ: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entsize: u32,
+}
+
+impl SectionHeader {
+    fn write(&self, buf: &mut Vec<u8>) {
+        for &val in &[self.name, self.typ, self.flags, self.addr, self.offset,
+                      self.size, self.link, self.info, self.align, self.entsize] {
+            put_u32(buf, val);
+        }
+    }
+}
+
+// sections are at their base addresses in the image
+//   every section with something in it gets a progbits section and a load segment
+//   labels and constants go in the symbol table, register aliases dont
+pub(crate) fn generate_elf(
+    image: &MemoryImage,
+    symbols: &SymbolTable,
+    entry: Option<u64>,
+    settings: &CompileSettings
+) -> Result<Vec<u8>, CompileError> {
+    let mut contents = Vec::new();
+    for &section in &Section::ALL {
+        let in_section = image.section(section);
+        if in_section.is_empty() && section != Section::Text {
+            continue;
+        }
+        let (addr, bytes) = binary::flat_image(&in_section, settings.bin_fill);
+        contents.push((section, to_u32(section.base() + addr)?, bytes));
+    }
+
+    // null, the progbits sections, then symtab, strtab, shstrtab
+    let symtab_index = contents.len() + 1;
+    let section_index = | section: Section | {
+        contents.iter()
+                .position(| (s, _, _) | *s == section)
+                .map(| at | at as u16 + 1)
+                .unwrap_or(SHN_ABS)
+    };
+
+    let mut strtab = StringTable::new();
+    let mut syms: Vec<(u32, u32, u16)> = Vec::new();
+    let mut named: Vec<(&String, _)> = symbols.iter().collect();
+    named.sort_by_key(| (name, symbol) | (symbol.value, name.to_string()));
+    for (name, symbol) in named {
+        let (value, shndx) =
+            match symbol.kind {
+                SymbolKind::Address(section) => {
+                    let unit = settings.for_section(section).address_unit_size();
+                    (to_u32(section.base() + symbol.value as u64 * unit)?, section_index(section))
+                }
+                SymbolKind::Constant => (symbol.value as u32, SHN_ABS),
+                SymbolKind::Register => continue,
+            };
+        syms.push((strtab.add(name), value, shndx));
+    }
+
+    let mut shstrtab = StringTable::new();
+    let section_names: Vec<u32> = contents.iter()
+                                          .map(| (section, _, _) | shstrtab.add(&format!(".{}", section.name())))
+                                          .collect();
+    let symtab_name = shstrtab.add(".symtab");
+    let strtab_name = shstrtab.add(".strtab");
+    let shstrtab_name = shstrtab.add(".shstrtab");
+
+    // file layout
+    let phoff = EHDR_SIZE;
+    let mut offset = phoff + PHDR_SIZE * contents.len();
+    let mut content_offsets = Vec::with_capacity(contents.len());
+    for (_, _, bytes) in &contents {
+        content_offsets.push(offset);
+        offset += bytes.len();
+    }
+    let symtab_offset = offset.div_ceil(4) * 4;
+    let symtab_size = SYM_SIZE * (syms.len() + 1);
+    let strtab_offset = symtab_offset + symtab_size;
+    let shstrtab_offset = strtab_offset + strtab.bytes.len();
+    let shoff = (shstrtab_offset + shstrtab.bytes.len()).div_ceil(4) * 4;
+    let shnum = contents.len() + 4;
+
+    let mut buf = Vec::with_capacity(shoff + SHDR_SIZE * shnum);
+
+    // elf header
+    buf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1, 0]);
+    buf.resize(16, 0);
+    put_u16(&mut buf, ET_EXEC);
+    put_u16(&mut buf, settings.elf_machine);
+    put_u32(&mut buf, 1);
+    put_u32(&mut buf, to_u32(entry.unwrap_or(0))?);
+    put_u32(&mut buf, phoff as u32);
+    put_u32(&mut buf, shoff as u32);
+    put_u32(&mut buf, settings.elf_flags);
+    put_u16(&mut buf, EHDR_SIZE as u16);
+    put_u16(&mut buf, PHDR_SIZE as u16);
+    put_u16(&mut buf, contents.len() as u16);
+    put_u16(&mut buf, SHDR_SIZE as u16);
+    put_u16(&mut buf, shnum as u16);
+    put_u16(&mut buf, shnum as u16 - 1);
+
+    // program headers
+    for ((section, addr, bytes), &at) in contents.iter().zip(content_offsets.iter()) {
+        let flags =
+            if *section == Section::Text {
+                PF_R | PF_X
+            } else {
+                PF_R | PF_W
+            };
+        for &val in &[PT_LOAD, at as u32, *addr, *addr, bytes.len() as u32, bytes.len() as u32, flags, 1] {
+            put_u32(&mut buf, val);
+        }
+    }
+
+    for (_, _, bytes) in &contents {
+        buf.extend_from_slice(bytes);
+    }
+
+    // symbols, after the null one
+    buf.resize(symtab_offset + SYM_SIZE, 0);
+    for &(name, value, shndx) in &syms {
+        put_u32(&mut buf, name);
+        put_u32(&mut buf, value);
+        put_u32(&mut buf, 0);
+        buf.push((STB_GLOBAL << 4) | STT_NOTYPE);
+        buf.push(0);
+        put_u16(&mut buf, shndx);
+    }
+    buf.extend_from_slice(&strtab.bytes);
+    buf.extend_from_slice(&shstrtab.bytes);
+    buf.resize(shoff + SHDR_SIZE, 0);
+
+    // section headers, after the null one
+    for (((section, addr, bytes), &at), &name) in contents.iter().zip(content_offsets.iter()).zip(section_names.iter()) {
+        let flags =
+            if *section == Section::Text {
+                SHF_ALLOC | SHF_EXECINSTR
+            } else {
+                SHF_ALLOC | SHF_WRITE
+            };
+        SectionHeader {
+            name,
+            typ: SHT_PROGBITS,
+            flags,
+            addr: *addr,
+            offset: at as u32,
+            size: bytes.len() as u32,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        }.write(&mut buf);
+    }
+    SectionHeader {
+        name: symtab_name,
+        typ: SHT_SYMTAB,
+        flags: 0,
+        addr: 0,
+        offset: symtab_offset as u32,
+        size: symtab_size as u32,
+        link: symtab_index as u32 + 1,
+        // every symbol but the null one is global
+        info: 1,
+        align: 4,
+        entsize: SYM_SIZE as u32,
+    }.write(&mut buf);
+    SectionHeader {
+        name: strtab_name,
+        typ: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: strtab_offset as u32,
+        size: strtab.bytes.len() as u32,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    }.write(&mut buf);
+    SectionHeader {
+        name: shstrtab_name,
+        typ: SHT_STRTAB,
+        flags: 0,
+        addr: 0,
+        offset: shstrtab_offset as u32,
+        size: shstrtab.bytes.len() as u32,
+        link: 0,
+        info: 0,
+        align: 1,
+        entsize: 0,
+    }.write(&mut buf);
+
+    Ok(buf)
+}
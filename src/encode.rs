@@ -0,0 +1,517 @@
+//! operand expressions and turning them into bits and bytes
+
+use std::convert::TryFrom;
+use std::fmt::Debug;
+use std::mem;
+use std::sync::Arc;
+
+use num::{PrimInt, ToPrimitive, Unsigned};
+
+use crate::compile::{ByteOrder, CompileSettings, Section, SymbolKind, SymbolTable};
+use crate::errors::CompileError;
+use crate::isa::{ArgKind, Opdef};
+
+/// bit fiddling for opcodes
+pub trait Bits: Debug + PrimInt + Unsigned {
+    // has to be unsigned
+    //   1. using them as bytes anyway
+    //   2. max_value is all ones
+
+    /// the low bits of val
+    fn from_u64(val: u64) -> Self {
+       Self::from(val & Self::max_value().to_u64().unwrap()).unwrap()
+    }
+
+    /// the low ct bits set
+    fn mask(ct: usize) -> Self {
+        !(Self::max_value() << ct)
+    }
+
+    /// if bit at is set
+    fn is_bit_set(self, at: usize) -> bool {
+        self >> at & Self::one() == Self::one()
+    }
+
+    /// scatters the low bits of val into the bits set in self, lowest first
+    fn eat(mut self, mut val: Self) -> Self {
+        let bit_sz = mem::size_of::<Self>() * 8;
+        let mut ret = Self::zero();
+        for i in 0..bit_sz {
+            if self & Self::one() == Self::one() {
+                ret = ret | ((val & Self::one()) << i);
+                val = val >> 1;
+            }
+            self = self >> 1;
+        }
+        ret
+    }
+
+    /// inverse of eat
+    fn spit(mut self, mut val: Self) -> Self {
+        let bit_sz = mem::size_of::<Self>() * 8;
+        let mut ret = Self::zero();
+        let mut at = 0;
+        for _ in 0..bit_sz {
+            if self & Self::one() == Self::one() {
+                ret = ret | ((val & Self::one()) << at);
+                at += 1;
+            }
+            self = self >> 1;
+            val = val >> 1;
+        }
+        ret
+    }
+
+    /// the low ct bytes, most significant first
+    fn to_bytes(mut self, ct: usize) -> Vec<u8>
+    where
+        Self: ToPrimitive {
+        let mut ret = vec![0u8; ct];
+        for i in (0..ct).rev() {
+            ret[i] = (self & Self::from(0xff).unwrap()).to_u8().unwrap();
+            self = self >> 8;
+        }
+        ret
+    }
+}
+
+// impl Bits for u8 {}
+// impl Bits for u16 {}
+// impl Bits for u32 {}
+impl Bits for u64 {}
+
+//
+
+/// one operand operators
+#[derive(Copy, Clone, Debug)]
+pub enum UnaryOp {
+    /// `(- a)`
+    Neg,
+    /// `(~ a)`
+    Not,
+    /// bits 0 to 7
+    Lo8,
+    /// bits 8 to 15
+    Hi8,
+    /// bits 16 to 23
+    Hh8,
+    /// program memory address, ie a byte address in address units
+    Pm,
+}
+
+/// two operand operators, folded left to right with more operands
+#[derive(Copy, Clone, Debug)]
+pub enum BinaryOp {
+    /// `+`
+    Add,
+    /// `-`
+    Sub,
+    /// `*`
+    Mul,
+    /// `/`, truncating
+    Div,
+    /// `mod`, with the sign of the left operand
+    Mod,
+    /// `<<`
+    Shl,
+    /// `>>`, arithmetic
+    Shr,
+    /// `&`
+    And,
+    /// `|`
+    Or,
+    /// `^`
+    Xor,
+}
+
+/// an operand expression, resolved once every symbol is known
+#[derive(Clone, Debug)]
+pub enum IArg {
+    /// a number
+    Raw(i64),
+    /// a symbol, resolved once every symbol is known
+    Label(String),
+    /// address of the current instruction
+    Here,
+    /// an operator on one expression
+    Unary(UnaryOp, Box<IArg>),
+    /// an operator on two expressions
+    Binary(BinaryOp, Box<IArg>, Box<IArg>),
+}
+
+impl IArg {
+    // labels are in address units for jumps
+    //   and in bytes when used as immediates, ie for lpm and data
+    pub(crate) fn resolve(
+        &self,
+        addr: u64,
+        kind: ArgKind,
+        symbols: &SymbolTable,
+        settings: &CompileSettings
+    ) -> Result<i64, CompileError> {
+        let scale =
+            if kind == ArgKind::Immediate {
+                settings.address_unit_size() as i64
+            } else {
+                1
+            };
+        if matches!(kind, ArgKind::Absolute | ArgKind::Relative) {
+            if let Some((name, kind)) = self.data_label(symbols) {
+                return Err(CompileError::WrongSymbolKind(name.to_string(), kind));
+            }
+        }
+        if kind != ArgKind::Register {
+            return self.eval(addr, scale, symbols);
+        }
+
+        match self {
+            IArg::Label(name) => {
+                let symbol = symbols.get(name)?;
+                if symbol.kind == SymbolKind::Register {
+                    Ok(symbol.value)
+                } else {
+                    Err(CompileError::WrongSymbolKind(name.clone(), symbol.kind))
+                }
+            }
+            _ => self.eval(addr, scale, symbols),
+        }
+    }
+
+    // the section if this is just an address, a label or here
+    pub(crate) fn address_section(&self, section: Section, symbols: &SymbolTable) -> Option<Section> {
+        match self {
+            IArg::Label(name) => {
+                match symbols.get(name).ok()?.kind {
+                    SymbolKind::Address(label_section) => Some(label_section),
+                    _ => None,
+                }
+            }
+            IArg::Here => Some(section),
+            _ => None,
+        }
+    }
+
+    // the first label outside text, code cant jump there
+    fn data_label(&self, symbols: &SymbolTable) -> Option<(&str, SymbolKind)> {
+        match self {
+            IArg::Label(name) => {
+                match symbols.get(name).ok()?.kind {
+                    SymbolKind::Address(section) if section != Section::Text => Some((name, SymbolKind::Address(section))),
+                    _ => None,
+                }
+            }
+            IArg::Unary(_, arg) => arg.data_label(symbols),
+            IArg::Binary(_, lhs, rhs) => {
+                lhs.data_label(symbols)
+                   .or_else(|| rhs.data_label(symbols))
+            }
+            _ => None,
+        }
+    }
+
+    fn eval(&self, addr: u64, scale: i64, symbols: &SymbolTable) -> Result<i64, CompileError> {
+        let overflow = || CompileError::ExpressionOverflow(addr);
+
+        match self {
+            IArg::Raw(val) => Ok(*val),
+            IArg::Label(name) => {
+                let symbol = symbols.get(name)?;
+                match symbol.kind {
+                    // only text counts in words, the other sections are already bytes
+                    SymbolKind::Address(Section::Text) => symbol.value.checked_mul(scale).ok_or_else(overflow),
+                    SymbolKind::Address(_) => Ok(symbol.value),
+                    SymbolKind::Constant => Ok(symbol.value),
+                    SymbolKind::Register => Err(CompileError::WrongSymbolKind(name.clone(), symbol.kind)),
+                }
+            }
+            IArg::Here => (addr as i64).checked_mul(scale).ok_or_else(overflow),
+            IArg::Unary(op, arg) => {
+                let val = arg.eval(addr, scale, symbols)?;
+                match op {
+                    UnaryOp::Neg => val.checked_neg().ok_or_else(overflow),
+                    UnaryOp::Not => Ok(!val),
+                    UnaryOp::Lo8 => Ok(val & 0xff),
+                    UnaryOp::Hi8 => Ok((val >> 8) & 0xff),
+                    UnaryOp::Hh8 => Ok((val >> 16) & 0xff),
+                    UnaryOp::Pm  => Ok(val / scale),
+                }
+            }
+            IArg::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(addr, scale, symbols)?;
+                let rhs = rhs.eval(addr, scale, symbols)?;
+                let shift = || u32::try_from(rhs).ok().filter(| &rhs | rhs < 64);
+                match op {
+                    BinaryOp::Add => lhs.checked_add(rhs).ok_or_else(overflow),
+                    BinaryOp::Sub => lhs.checked_sub(rhs).ok_or_else(overflow),
+                    BinaryOp::Mul => lhs.checked_mul(rhs).ok_or_else(overflow),
+                    BinaryOp::Div | BinaryOp::Mod if rhs == 0 => {
+                        Err(CompileError::DivisionByZero(addr))
+                    }
+                    BinaryOp::Div => lhs.checked_div(rhs).ok_or_else(overflow),
+                    BinaryOp::Mod => lhs.checked_rem(rhs).ok_or_else(overflow),
+                    BinaryOp::Shl => {
+                        let shift = shift().ok_or_else(overflow)?;
+                        let val = lhs << shift;
+                        if val >> shift == lhs {
+                            Ok(val)
+                        } else {
+                            Err(overflow())
+                        }
+                    }
+                    BinaryOp::Shr => shift().map(| shift | lhs >> shift).ok_or_else(overflow),
+                    BinaryOp::And => Ok(lhs & rhs),
+                    BinaryOp::Or  => Ok(lhs | rhs),
+                    BinaryOp::Xor => Ok(lhs ^ rhs),
+                }
+            }
+        }
+    }
+}
+
+/// an opdef and its operands
+#[derive(Debug)]
+pub struct Instruction {
+    /// the form picked for the operands
+    pub opdef: Arc<Opdef>,
+    /// one per opdef operand
+    pub args: Vec<IArg>,
+}
+
+impl Instruction {
+    pub(crate) fn resolve_args(
+        &self,
+        addr: u64,
+        symbols: &SymbolTable,
+        settings: &CompileSettings
+    ) -> Result<Vec<i64>, CompileError> {
+        self.args.iter()
+                 .zip(self.opdef.args.iter())
+                 .map(| (arg, oparg) | {
+                     let val = arg.resolve(addr, oparg.kind, symbols, settings)?;
+                     if oparg.kind != ArgKind::Relative {
+                         return Ok(val);
+                     }
+
+                     // relative to the word after this one
+                     let next = (addr + settings.words_to_units(1)) as i64;
+                     let offset = val.checked_sub(next)
+                                     .ok_or(CompileError::ExpressionOverflow(addr))?;
+                     if oparg.encode(offset).is_none() {
+                         let (min, max) = oparg.transform.range(oparg.bits());
+                         return Err(CompileError::BranchOutOfRange {
+                             instruction: self.opdef.name.clone(),
+                             addr,
+                             target: val,
+                             offset,
+                             min,
+                             max,
+                         });
+                     }
+                     Ok(offset)
+                 })
+                 .collect()
+    }
+}
+
+//
+
+/// items are item_size bytes each
+///   the whole run is padded with zeros out to an address unit
+///   so a word in text, and not at all in byte addressed sections
+#[derive(Debug)]
+pub struct RawData {
+    /// 1 for db, 2 for dw, 4 for dd
+    pub item_size: usize,
+    /// each one resolved like an immediate
+    pub items: Vec<IArg>,
+}
+
+impl RawData {
+    /// in bytes
+    pub fn size(&self, settings: &CompileSettings) -> usize {
+        let unit = settings.address_unit_size() as usize;
+        (self.items.len() * self.item_size).div_ceil(unit) * unit
+    }
+
+    pub(crate) fn to_bytes(
+        &self,
+        addr: u64,
+        symbols: &SymbolTable,
+        settings: &CompileSettings
+    ) -> Result<Vec<u8>, CompileError> {
+        let bits = self.item_size * 8;
+        let min = -(1 << (bits - 1));
+        let max = u64::mask(bits) as i64;
+
+        let mut bytes = Vec::with_capacity(self.size(settings));
+        for item in &self.items {
+            // byte addresses, like immediates
+            //   pm gives word addresses
+            let val = item.resolve(addr, ArgKind::Immediate, symbols, settings)?;
+            if val < min || val > max {
+                return Err(CompileError::DataOutOfRange {
+                    addr,
+                    value: val,
+                    min,
+                    max,
+                });
+            }
+
+            let mut item_bytes = (val as u64).to_bytes(self.item_size);
+            if settings.byte_order == ByteOrder::Little {
+                item_bytes.reverse();
+            }
+            bytes.extend(item_bytes);
+        }
+        bytes.resize(self.size(settings), 0);
+
+        Ok(bytes)
+    }
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+    use crate::compile::{self, CodeObject, Symbol};
+    use crate::frontend;
+
+    // text bytes from org 0
+    fn assemble(source: &str) -> Result<Vec<u8>, CompileError> {
+        let code = frontend::read_program(source, &avr::instruction_set()).unwrap();
+        let (_, bytes) = compile::compile_bin(&avr::compile_settings(), &code)?;
+        Ok(bytes)
+    }
+
+    // without the position
+    fn error(source: &str) -> CompileError {
+        match assemble(source).unwrap_err() {
+            CompileError::At(_, err) => *err,
+            err => err,
+        }
+    }
+
+    #[test]
+    fn branch_reach() {
+        // 2K words either way, counted from the word after the branch
+        assert_eq!(assemble("(org 0) (rjmp (+ . 2048))").unwrap(), [0xff, 0xc7]);
+        assert_eq!(assemble("(org 0) (rjmp (- . 2047))").unwrap(), [0x00, 0xc8]);
+        assert_eq!(assemble("(org 0) (rcall (+ . 2048))").unwrap(), [0xff, 0xd7]);
+        assert_eq!(assemble("(org 0) (rcall (- . 2047))").unwrap(), [0x00, 0xd8]);
+        assert!(matches!(error("(org 0) (rjmp (+ . 2049))"), CompileError::BranchOutOfRange { offset: 2048, .. }));
+        assert!(matches!(error("(org 0) (rjmp (- . 2048))"), CompileError::BranchOutOfRange { offset: -2049, .. }));
+        assert!(matches!(error("(org 0) (rcall (+ . 2049))"), CompileError::BranchOutOfRange { offset: 2048, .. }));
+        assert!(matches!(error("(org 0) (rcall (- . 2048))"), CompileError::BranchOutOfRange { offset: -2049, .. }));
+
+        // conditional branches only reach -64..=63
+        assert_eq!(assemble("(org 0) (breq (+ . 64))").unwrap(), [0xf9, 0xf1]);
+        assert_eq!(assemble("(org 0) (brne (- . 63))").unwrap(), [0x01, 0xf6]);
+        assert!(matches!(error("(org 0) (breq (+ . 65))"), CompileError::BranchOutOfRange { offset: 64, min: -64, max: 63, .. }));
+        assert!(matches!(error("(org 0) (brne (- . 64))"), CompileError::BranchOutOfRange { offset: -65, .. }));
+
+        // a target so far back the offset doesnt fit in an i64
+        assert!(matches!(error("(org 0) (rjmp (- -9223372036854775807 1))"), CompileError::ExpressionOverflow(0)));
+    }
+    #[test]
+    fn jumps_only_go_to_text() {
+        let data = "(org 0) (section data) (label x) (db 1) (section text) ";
+        for jump in &["(rjmp x)", "(rcall (+ x 1))", "(breq x)", "(jmp x)", "(call x)"] {
+            let err = error(&format!("{}{}", data, jump));
+            assert!(matches!(err, CompileError::WrongSymbolKind(name, SymbolKind::Address(Section::Data)) if name == "x"), "{}", jump);
+        }
+        // data addresses are fine as data addresses
+        assert_eq!(assemble(&format!("{}(lds r16 x)", data)).unwrap(), [0x00, 0x91, 0x00, 0x00]);
+    }
+    // an expression as the one item of a dd
+    fn expr(source: &str) -> IArg {
+        let code = frontend::read_program(&format!("(dd {})", source), &avr::instruction_set()).unwrap();
+        match code.into_iter().next() {
+            Some((_, CodeObject::RawData(mut data))) => data.items.remove(0),
+            _ => unreachable!(),
+        }
+    }
+
+    // at word 0x10 in text
+    fn resolve(source: &str, kind: ArgKind) -> Result<i64, CompileError> {
+        let mut symbols = SymbolTable::default();
+        let mut define = | name, kind, value | {
+            symbols.define(name, Symbol { kind, value, redefinable: false }).unwrap();
+        };
+        define("start", SymbolKind::Address(Section::Text), 0x123);
+        define("table", SymbolKind::Address(Section::Data), 0x160);
+        define("size", SymbolKind::Constant, 10);
+        define("tmp", SymbolKind::Register, 16);
+
+        expr(source).resolve(0x10, kind, &symbols, &avr::compile_settings())
+    }
+
+    fn value(source: &str) -> i64 {
+        resolve(source, ArgKind::Immediate).unwrap()
+    }
+
+    #[test]
+    fn operators() {
+        // more than two operands fold from the left
+        assert_eq!(value("(- 10 3 2)"), 5);
+        assert_eq!(value("(/ 100 5 2)"), 10);
+        assert_eq!(value("(+ 1 (* 2 3) size)"), 17);
+        assert_eq!(value("(- size)"), -10);
+        assert_eq!(value("(mod -7 3)"), -1);
+        assert_eq!(value("(<< 1 (+ 2 1))"), 8);
+        assert_eq!(value("(>> -16 2)"), -4);
+        assert_eq!(value("(& #xff0 (~ #xf0))"), 0xf00);
+        assert_eq!(value("(logior #x0f #xf0)"), 0xff);
+        assert_eq!(value("(^ #xff #x0f)"), 0xf0);
+    }
+
+    #[test]
+    fn text_labels_scale_as_immediates() {
+        // bytes as immediates, words as addresses
+        assert_eq!(resolve("start", ArgKind::Immediate).unwrap(), 0x246);
+        assert_eq!(resolve("start", ArgKind::Address).unwrap(), 0x123);
+        assert_eq!(resolve(".", ArgKind::Immediate).unwrap(), 0x20);
+        assert_eq!(resolve(".", ArgKind::Relative).unwrap(), 0x10);
+        assert_eq!(value("(- . start)"), 0x20 - 0x246);
+
+        // pm is back to words
+        assert_eq!(value("(pm start)"), 0x123);
+        assert_eq!(value("(lo8 start)"), 0x46);
+        assert_eq!(value("(hi8 start)"), 0x02);
+        assert_eq!(value("(lo8 (pm start))"), 0x23);
+        assert_eq!(value("(hi8 (pm start))"), 0x01);
+        assert_eq!(value("(hh8 (<< start 8))"), 0x02);
+
+        // other sections are bytes already
+        assert_eq!(resolve("table", ArgKind::Immediate).unwrap(), 0x160);
+        assert_eq!(resolve("table", ArgKind::Address).unwrap(), 0x160);
+        assert_eq!(value("(+ size 1)"), 11);
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        assert!(matches!(resolve("(/ 1 0)", ArgKind::Immediate), Err(CompileError::DivisionByZero(0x10))));
+        assert!(matches!(resolve("(mod 1 (- size 10))", ArgKind::Immediate), Err(CompileError::DivisionByZero(0x10))));
+
+        let overflows = [
+            "(+ 9223372036854775807 1)",
+            "(- -9223372036854775807 2)",
+            "(* 4611686018427387904 2)",
+            "(- (- -9223372036854775807 1))",
+            "(/ (- -9223372036854775807 1) -1)",
+            "(<< 1 64)",
+            "(<< 1 -1)",
+            "(<< #x4000000000000000 1)",
+            "(>> 1 64)",
+        ];
+        for source in &overflows {
+            assert!(matches!(resolve(source, ArgKind::Immediate), Err(CompileError::ExpressionOverflow(0x10))), "{}", source);
+        }
+
+        assert!(matches!(resolve("(+ tmp 1)", ArgKind::Immediate), Err(CompileError::WrongSymbolKind(..))));
+        assert!(matches!(resolve("size", ArgKind::Register), Err(CompileError::WrongSymbolKind(..))));
+        assert_eq!(resolve("tmp", ArgKind::Register).unwrap(), 16);
+        assert!(matches!(resolve("nope", ArgKind::Immediate), Err(CompileError::SymbolNotFound(name)) if name == "nope"));
+    }
+}
@@ -0,0 +1,244 @@
+//! errors from each stage
+//!
+//! reading source gives a [`SourceError`], compiling gives a [`CompileError`]
+
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::compile::{Section, SymbolKind};
+use crate::hexfmt::HexFormat;
+use crate::reader::Position;
+
+pub use crate::frontend::SourceError;
+pub use crate::hexfmt::{HexParseError, HexReadError};
+pub use crate::reader::ReadError;
+pub use crate::srec::{SRecParseError, SRecReadError};
+
+/// anything that stops code from laying out or encoding
+#[derive(Debug)]
+pub enum CompileError {
+    /// the form the error came from
+    At(Position, Box<CompileError>),
+    /// no forms at all
+    EmptyProgram,
+    /// code has to start with an org
+    StartWithAddressTag,
+    /// a label defined twice
+    DuplicateLabel(String),
+    /// used but never defined
+    SymbolNotFound(String),
+    /// defined again, when it or the new one cant be
+    Redefinition {
+        /// symbol being defined
+        name: String,
+        /// kind of the new definition
+        kind: SymbolKind,
+        /// kind of the one already there
+        previous: SymbolKind,
+    },
+    /// a symbol used where its kind doesnt fit, like a register in an expression
+    WrongSymbolKind(String, SymbolKind),
+    /// an opdef applied to the wrong number of operands
+    OperandCount {
+        /// opdef name
+        instruction: String,
+        /// operands the opdef has
+        expected: usize,
+        /// operands it was given
+        got: usize,
+    },
+    /// an operand that doesnt fit its field
+    OperandOutOfRange {
+        /// opdef name
+        instruction: String,
+        /// which operand, from 0
+        operand: usize,
+        /// the operand, before its transform
+        value: i64,
+        /// smallest that fits
+        min: i64,
+        /// largest that fits
+        max: i64,
+    },
+    /// a relative jump that doesnt reach
+    BranchOutOfRange {
+        /// opdef name
+        instruction: String,
+        /// the jump, in address units
+        addr: u64,
+        /// where it jumps to
+        target: i64,
+        /// in words from the next instruction
+        offset: i64,
+        /// furthest back it reaches
+        min: i64,
+        /// furthest forward it reaches
+        max: i64,
+    },
+    /// a data item that doesnt fit its size
+    DataOutOfRange {
+        /// the data, in address units
+        addr: u64,
+        /// the item
+        value: i64,
+        /// smallest that fits
+        min: i64,
+        /// largest that fits
+        max: i64,
+    },
+    /// division or mod by zero, at this address
+    DivisionByZero(u64),
+    /// an expression that doesnt fit in an i64, at this address
+    ExpressionOverflow(u64),
+    /// an output address past what the hex format reaches
+    HexAddressOutOfRange(u64, HexFormat),
+    /// I8HEX has no start record
+    StartAddressUnsupported(HexFormat),
+    /// an output address past 32 bits
+    SRecordAddressOutOfRange(u64),
+    /// an output address past 32 bits
+    ElfAddressOutOfRange(u64),
+    /// a byte in a memory image written twice
+    ImageOverlap(u64),
+    /// addresses are in the section address units
+    ///   labels are the last ones at or before the address
+    OverlappingCode {
+        /// where the code is
+        section: Section,
+        /// the code that overlaps
+        addr: u64,
+        /// label near the code that overlaps
+        label: Option<String>,
+        /// the code already there
+        previous: u64,
+        /// label near the code already there
+        previous_label: Option<String>,
+    },
+    /// code past the last address of its section
+    AddressOverflow {
+        /// where the code is
+        section: Section,
+        /// start of the code
+        addr: u64,
+        /// label near the code
+        label: Option<String>,
+        /// one past the end of the code
+        end: u64,
+        /// last address in the section
+        max: u64,
+    },
+    /// an instruction the device core doesnt have
+    MissingInstruction {
+        /// opdef name
+        instruction: String,
+        /// device name
+        device: &'static str,
+        /// core name
+        core: &'static str,
+    },
+    /// addr is the first byte outside start..end, in section bytes
+    DoesntFit {
+        /// device name
+        device: &'static str,
+        /// where the byte is
+        section: Section,
+        /// the byte
+        addr: u64,
+        /// first byte in the device memory
+        start: u64,
+        /// one past the last byte in the device memory
+        end: u64,
+    },
+}
+
+impl CompileError {
+    /// keeps the innermost position
+    pub fn at(self, pos: Position) -> Self {
+        match self {
+            CompileError::At(..) => self,
+            _ => CompileError::At(pos, Box::new(self)),
+        }
+    }
+}
+
+fn near(label: &Option<String>) -> String {
+    match label {
+        Some(label) => format!(" (near '{}')", label),
+        None => String::new(),
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CompileError::*;
+
+        match self {
+            At(pos, err) => write!(f, "{}: {}", pos, err),
+            EmptyProgram => write!(f, "program has no code"),
+            StartWithAddressTag => write!(f, "code must start with an address tag"),
+            DuplicateLabel(name) => write!(f, "duplicate label '{}'", name),
+            SymbolNotFound(name) => write!(f, "symbol '{}' not found", name),
+            Redefinition { name, kind, previous } => {
+                write!(f, "cant redefine '{}' as {:?}, its already defined as {:?}", name, kind, previous)
+            }
+            WrongSymbolKind(name, kind) => {
+                write!(f, "'{}' is a {:?} symbol and cant be used here", name, kind)
+            }
+            OperandCount { instruction, expected, got } => {
+                write!(f, "{} takes {} operand(s), got {}", instruction, expected, got)
+            }
+            OperandOutOfRange { instruction, operand, value, min, max } => {
+                write!(f, "{}: operand {} is {}, allowed range is {}..={}",
+                       instruction, operand, value, min, max)
+            }
+            BranchOutOfRange { instruction, addr, target, offset, min, max } => {
+                write!(f, "{} at {:#x}: target {:#x} is {} words away, allowed range is {}..={}",
+                       instruction, addr, target, offset, min, max)
+            }
+            DataOutOfRange { addr, value, min, max } => {
+                write!(f, "data at {:#x}: {} doesnt fit, allowed range is {}..={}",
+                       addr, value, min, max)
+            }
+            DivisionByZero(addr) => write!(f, "division by zero at {:#x}", addr),
+            ExpressionOverflow(addr) => write!(f, "expression overflows at {:#x}", addr),
+            HexAddressOutOfRange(addr, format) => {
+                write!(f, "address {:#x} cant be written as {:?}HEX", addr, format)
+            }
+            StartAddressUnsupported(format) => {
+                write!(f, "{:?}HEX has no start address record", format)
+            }
+            SRecordAddressOutOfRange(addr) => {
+                write!(f, "address {:#x} doesnt fit in an s-record", addr)
+            }
+            ElfAddressOutOfRange(addr) => {
+                write!(f, "address {:#x} doesnt fit in a 32 bit elf", addr)
+            }
+            ImageOverlap(addr) => write!(f, "byte at {:#x} is written more than once", addr),
+            OverlappingCode { section, addr, label, previous, previous_label } => {
+                write!(f, "{} at {:#x}{} overlaps code already at {:#x}{}",
+                       section.name(), addr, near(label), previous, near(previous_label))
+            }
+            AddressOverflow { section, addr, label, end, max } => {
+                write!(f, "{} at {:#x}{} runs to {:#x}, past the last address {:#x}",
+                       section.name(), addr, near(label), end, max)
+            }
+            MissingInstruction { instruction, device, core } => {
+                write!(f, "{} doesnt have {}, its core is {}", device, instruction, core)
+            }
+            DoesntFit { device, section, addr, start, end } => {
+                let memory =
+                    match section {
+                        Section::Text   => "flash",
+                        Section::Data   => "sram",
+                        Section::Eeprom => "eeprom",
+                        Section::Fuse   => "fuses",
+                    };
+                write!(f, "{} byte at {:#x} doesnt fit in {} {}, which is {:#x}..{:#x}",
+                       section.name(), addr, device, memory, start, end)
+            }
+        }
+    }
+}
+
+impl Error for CompileError {}
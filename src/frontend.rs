@@ -0,0 +1,377 @@
+//! reading s-expression source into code objects
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fmt::Display;
+
+use crate::reader::{self, Position, ReadError, Sexp, SexpKind};
+use crate::compile::{CodeObject, Section, SymbolDef, SymbolKind};
+use crate::encode::{BinaryOp, IArg, Instruction, RawData, UnaryOp};
+use std::sync::Arc;
+
+use crate::isa::{ArgKind, Isa, Opdef};
+
+/// a form that cant be read as code
+#[derive(Debug)]
+pub enum SourceError {
+    /// source isnt s-expressions
+    Read(ReadError),
+    /// not a list starting with a name
+    ExpectedForm(Position),
+    /// not a symbol
+    ExpectedName(Position),
+    /// not a number
+    ExpectedNumber(Position),
+    /// not a string
+    ExpectedString(Position),
+    /// a negative number where a count goes
+    ExpectedCount(Position),
+    /// a name thats not a directive or in the isa
+    UnknownInstruction(Position, String),
+    /// wrong number of operands
+    WrongArgCount {
+        /// where the form starts
+        pos: Position,
+        /// directive, instruction, or operator
+        name: String,
+        /// operands it takes
+        expected: usize,
+        /// operands it was given
+        got: usize,
+    },
+    /// operands that dont match any pointer form
+    NoMatchingForm(Position, String),
+    /// an unknown operator in an expression
+    UnknownOperator(Position, String),
+    /// not rN or a def alias
+    ExpectedRegister(Position),
+    /// a register or string in an expression
+    InvalidOperand(Position),
+    /// not text, data, eeprom, or fuse
+    UnknownSection(Position),
+}
+
+impl Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SourceError::*;
+
+        match self {
+            Read(err) => write!(f, "{}", err),
+            ExpectedForm(pos) => {
+                write!(f, "{}: expected a form like (name args...)", pos)
+            }
+            ExpectedName(pos) => write!(f, "{}: expected a name", pos),
+            ExpectedNumber(pos) => write!(f, "{}: expected a number", pos),
+            ExpectedString(pos) => write!(f, "{}: expected a string", pos),
+            ExpectedCount(pos) => write!(f, "{}: expected a number that isnt negative", pos),
+            UnknownInstruction(pos, name) => {
+                write!(f, "{}: unknown instruction '{}'", pos, name)
+            }
+            WrongArgCount { pos, name, expected, got } => {
+                write!(f, "{}: '{}' takes {} operand(s), got {}", pos, name, expected, got)
+            }
+            NoMatchingForm(pos, name) => {
+                write!(f, "{}: operands dont match any form of '{}'", pos, name)
+            }
+            UnknownOperator(pos, name) => write!(f, "{}: unknown operator '{}'", pos, name),
+            ExpectedRegister(pos) => write!(f, "{}: expected a register", pos),
+            InvalidOperand(pos) => write!(f, "{}: invalid operand", pos),
+            UnknownSection(pos) => write!(f, "{}: unknown section, expected text, data, eeprom, or fuse", pos),
+        }
+    }
+}
+
+impl Error for SourceError {}
+
+impl From<ReadError> for SourceError {
+    fn from(err: ReadError) -> Self {
+        SourceError::Read(err)
+    }
+}
+
+//
+
+fn expect_symbol(sexp: &Sexp) -> Result<&str, SourceError> {
+    if let SexpKind::Symbol(name) = &sexp.kind {
+        Ok(name)
+    } else {
+        Err(SourceError::ExpectedName(sexp.pos))
+    }
+}
+
+fn expect_number(sexp: &Sexp) -> Result<i64, SourceError> {
+    if let SexpKind::Number(num) = sexp.kind {
+        Ok(num)
+    } else {
+        Err(SourceError::ExpectedNumber(sexp.pos))
+    }
+}
+
+fn expect_count(sexp: &Sexp) -> Result<u64, SourceError> {
+    let num = expect_number(sexp)?;
+    u64::try_from(num).map_err(|_| SourceError::ExpectedCount(sexp.pos))
+}
+
+fn expect_args(pos: Position, name: &str, args: &[Sexp], expected: usize) -> Result<(), SourceError> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(SourceError::WrongArgCount {
+            pos,
+            name: name.to_string(),
+            expected,
+            got: args.len(),
+        })
+    }
+}
+
+fn parse_register(name: &str) -> Option<i64> {
+    let num = name.strip_prefix('r')
+                  .or_else(|| name.strip_prefix('R'))?;
+    if num.is_empty() || !num.bytes().all(| byte | byte.is_ascii_digit()) {
+        return None;
+    }
+    num.parse().ok()
+}
+
+fn pointer_matches(sexp: &Sexp, pointer: &str) -> bool {
+    matches!(&sexp.kind, SexpKind::Symbol(name) if name == pointer)
+}
+
+fn select_opdef<'a>(pos: Position, name: &str, forms: &'a [Arc<Opdef>], args: &[Sexp]) -> Result<&'a Arc<Opdef>, SourceError> {
+    let mut same_count = forms.iter()
+                              .filter(| opdef | opdef.args.len() == args.len())
+                              .peekable();
+
+    if same_count.peek().is_none() {
+        return Err(SourceError::WrongArgCount {
+            pos,
+            name: name.to_string(),
+            expected: forms[0].args.len(),
+            got: args.len(),
+        });
+    }
+
+    same_count.find(| opdef | {
+                  opdef.args.iter()
+                            .zip(args.iter())
+                            .all(| (arg, sexp) | {
+                                match arg.kind {
+                                    ArgKind::Pointer(pointer) => pointer_matches(sexp, pointer),
+                                    _ => true,
+                                }
+                            })
+              })
+              .ok_or_else(|| SourceError::NoMatchingForm(pos, name.to_string()))
+}
+
+fn read_expr(sexp: &Sexp) -> Result<IArg, SourceError> {
+    let items =
+        match &sexp.kind {
+            SexpKind::Number(num) => return Ok(IArg::Raw(*num)),
+            SexpKind::Symbol(name) if name == "." => return Ok(IArg::Here),
+            SexpKind::Symbol(name) => {
+                if parse_register(name).is_some() {
+                    return Err(SourceError::InvalidOperand(sexp.pos));
+                }
+                return Ok(IArg::Label(name.clone()));
+            }
+            SexpKind::Str(_) => return Err(SourceError::InvalidOperand(sexp.pos)),
+            SexpKind::List(items) => items,
+        };
+
+    let (head, args) = items.split_first()
+                            .ok_or(SourceError::ExpectedForm(sexp.pos))?;
+    let name = expect_symbol(head)?;
+
+    let unary =
+        match name {
+            "-" if args.len() == 1 => Some(UnaryOp::Neg),
+            "~" | "lognot"         => Some(UnaryOp::Not),
+            "lo8"                  => Some(UnaryOp::Lo8),
+            "hi8"                  => Some(UnaryOp::Hi8),
+            "hh8"                  => Some(UnaryOp::Hh8),
+            "pm"                   => Some(UnaryOp::Pm),
+            _ => None,
+        };
+    if let Some(op) = unary {
+        expect_args(sexp.pos, name, args, 1)?;
+        return Ok(IArg::Unary(op, Box::new(read_expr(&args[0])?)));
+    }
+
+    let binary =
+        match name {
+            "+"              => BinaryOp::Add,
+            "-"              => BinaryOp::Sub,
+            "*"              => BinaryOp::Mul,
+            "/"              => BinaryOp::Div,
+            "mod"            => BinaryOp::Mod,
+            "<<"             => BinaryOp::Shl,
+            ">>"             => BinaryOp::Shr,
+            "&" | "logand"   => BinaryOp::And,
+            "|" | "logior"   => BinaryOp::Or,
+            "^" | "logxor"   => BinaryOp::Xor,
+            _ => return Err(SourceError::UnknownOperator(head.pos, name.to_string())),
+        };
+    if args.len() < 2 {
+        return Err(SourceError::WrongArgCount {
+            pos: sexp.pos,
+            name: name.to_string(),
+            expected: 2,
+            got: args.len(),
+        });
+    }
+
+    // left to right, so (- a b c) is a - b - c
+    let mut ret = read_expr(&args[0])?;
+    for arg in &args[1..] {
+        ret = IArg::Binary(binary, Box::new(ret), Box::new(read_expr(arg)?));
+    }
+    Ok(ret)
+}
+
+fn read_operand(sexp: &Sexp, kind: ArgKind) -> Result<IArg, SourceError> {
+    match (&sexp.kind, kind) {
+        (_, ArgKind::Pointer(_)) => Ok(IArg::Raw(0)),
+        // anything that isnt rN is a register alias from def
+        (SexpKind::Symbol(name), ArgKind::Register) => {
+            Ok(parse_register(name).map(IArg::Raw)
+                                   .unwrap_or_else(|| IArg::Label(name.clone())))
+        }
+        (_, ArgKind::Register) => Err(SourceError::ExpectedRegister(sexp.pos)),
+        _ => read_expr(sexp),
+    }
+}
+
+// strings are only allowed in byte data
+fn read_data(args: &[Sexp], item_size: usize) -> Result<RawData, SourceError> {
+    let mut items = Vec::new();
+    for sexp in args {
+        match &sexp.kind {
+            SexpKind::Str(bytes) if item_size == 1 => {
+                items.extend(bytes.iter().map(| &byte | IArg::Raw(byte as i64)));
+            }
+            _ => items.push(read_operand(sexp, ArgKind::Address)?),
+        }
+    }
+    Ok(RawData { item_size, items })
+}
+
+fn read_string(args: &[Sexp], terminate: bool) -> Result<RawData, SourceError> {
+    let mut items = Vec::new();
+    for sexp in args {
+        if let SexpKind::Str(bytes) = &sexp.kind {
+            items.extend(bytes.iter().map(| &byte | IArg::Raw(byte as i64)));
+        } else {
+            return Err(SourceError::ExpectedString(sexp.pos));
+        }
+    }
+    if terminate {
+        items.push(IArg::Raw(0));
+    }
+    Ok(RawData { item_size: 1, items })
+}
+
+fn read_symbol_def(pos: Position, name: &str, args: &[Sexp], kind: SymbolKind, redefinable: bool) -> Result<SymbolDef, SourceError> {
+    expect_args(pos, name, args, 2)?;
+    let arg_kind =
+        if kind == SymbolKind::Register {
+            ArgKind::Register
+        } else {
+            ArgKind::Address
+        };
+
+    Ok(SymbolDef {
+        name: expect_symbol(&args[0])?.to_string(),
+        kind,
+        value: read_operand(&args[1], arg_kind)?,
+        redefinable,
+    })
+}
+
+fn read_form(sexp: &Sexp, isa: &Isa) -> Result<CodeObject, SourceError> {
+    let items =
+        if let SexpKind::List(items) = &sexp.kind {
+            items
+        } else {
+            return Err(SourceError::ExpectedForm(sexp.pos));
+        };
+
+    let (head, args) = items.split_first()
+                            .ok_or(SourceError::ExpectedForm(sexp.pos))?;
+    let name = expect_symbol(head)?;
+
+    match name {
+        "org" => {
+            expect_args(sexp.pos, name, args, 1)?;
+            Ok(CodeObject::AddressTag(expect_count(&args[0])?))
+        }
+        "label" => {
+            expect_args(sexp.pos, name, args, 1)?;
+            Ok(CodeObject::LabelTag(expect_symbol(&args[0])?.to_string()))
+        }
+        "equ" => Ok(CodeObject::SymbolTag(read_symbol_def(sexp.pos, name, args, SymbolKind::Constant, false)?)),
+        "set" => Ok(CodeObject::SymbolTag(read_symbol_def(sexp.pos, name, args, SymbolKind::Constant, true)?)),
+        "def" => Ok(CodeObject::SymbolTag(read_symbol_def(sexp.pos, name, args, SymbolKind::Register, true)?)),
+        "section" => {
+            expect_args(sexp.pos, name, args, 1)?;
+            let section = Section::from_name(expect_symbol(&args[0])?)
+                                  .ok_or(SourceError::UnknownSection(args[0].pos))?;
+            Ok(CodeObject::SectionTag(section))
+        }
+        "align" => {
+            expect_args(sexp.pos, name, args, 1)?;
+            Ok(CodeObject::Align(expect_count(&args[0])?))
+        }
+        "skip" => {
+            expect_args(sexp.pos, name, args, 1)?;
+            Ok(CodeObject::Skip(expect_count(&args[0])?))
+        }
+        "fill" => {
+            expect_args(sexp.pos, name, args, 2)?;
+            Ok(CodeObject::Fill {
+                count: expect_count(&args[0])?,
+                value: expect_count(&args[1])?,
+            })
+        }
+        "db"    => Ok(CodeObject::RawData(read_data(args, 1)?)),
+        "dw"    => Ok(CodeObject::RawData(read_data(args, 2)?)),
+        "dd"    => Ok(CodeObject::RawData(read_data(args, 4)?)),
+        "ascii" => Ok(CodeObject::RawData(read_string(args, false)?)),
+        "asciz" => Ok(CodeObject::RawData(read_string(args, true)?)),
+        _ => {
+            let forms = isa.get(name)
+                           .ok_or_else(|| SourceError::UnknownInstruction(head.pos, name.to_string()))?;
+            let opdef = select_opdef(sexp.pos, name, forms, args)?;
+            let args: Result<Vec<_>, _> = args.iter()
+                                              .zip(opdef.args.iter())
+                                              .map(| (sexp, arg) | read_operand(sexp, arg.kind))
+                                              .collect();
+            Ok(CodeObject::Instruction(Instruction { opdef: opdef.clone(), args: args? }))
+        }
+    }
+}
+
+/// one code object per top level form, with where the form starts
+pub fn read_program(source: &str, isa: &Isa) -> Result<Vec<(Position, CodeObject)>, SourceError> {
+    reader::read_all(source)?
+        .iter()
+        .map(| sexp | Ok((sexp.pos, read_form(sexp, isa)?)))
+        .collect()
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+
+    #[test]
+    fn org_isnt_negative() {
+        let err = read_program("(org 0) (nop)\n  (org -1)", &avr::instruction_set()).unwrap_err();
+        assert!(matches!(err, SourceError::ExpectedCount(Position { line: 2, col: 8 })));
+        assert!(matches!(read_program("(org #x10)", &avr::instruction_set()).unwrap()[0].1, CodeObject::AddressTag(0x10)));
+    }
+}
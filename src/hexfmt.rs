@@ -0,0 +1,492 @@
+//! intel hex records
+
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::compile::CompileSettings;
+use crate::errors::CompileError;
+use crate::image::MemoryImage;
+
+/// the record types, by their number in the record
+#[derive(Copy, Clone, Debug)]
+pub enum HexRecordType {
+    /// 00
+    Data,
+    /// 01, the last record
+    EndOfFile,
+    /// 02, bits 4 to 19 of the address
+    ExtendedSegmentAddress,
+    /// 03, CS:IP to start at
+    StartSegmentAddress,
+    /// 04, bits 16 to 31 of the address
+    ExtendedLinearAddress,
+    /// 05, address to start at
+    StartLinearAddress,
+}
+
+impl HexRecordType {
+    pub(crate) fn from_u8(typ: u8) -> Option<Self> {
+        use HexRecordType::*;
+
+        match typ {
+            0 => Some(Data),
+            1 => Some(EndOfFile),
+            2 => Some(ExtendedSegmentAddress),
+            3 => Some(StartSegmentAddress),
+            4 => Some(ExtendedLinearAddress),
+            5 => Some(StartLinearAddress),
+            _ => None,
+        }
+    }
+
+    // None if any length is ok
+    pub(crate) fn data_len(self) -> Option<usize> {
+        use HexRecordType::*;
+
+        match self {
+            Data                   => None,
+            EndOfFile              => Some(0),
+            ExtendedSegmentAddress => Some(2),
+            StartSegmentAddress    => Some(4),
+            ExtendedLinearAddress  => Some(2),
+            StartLinearAddress     => Some(4),
+        }
+    }
+}
+
+/// one intel hex line
+#[derive(Clone, Debug)]
+pub struct HexRecord {
+    /// what the record is
+    pub typ: HexRecordType,
+    /// low 16 bits of the address for data records, 0 otherwise
+    pub addr: u16,
+    /// bytes after the type
+    pub data: Vec<u8>,
+}
+
+impl HexRecord {
+    /// twos complement of the byte sum
+    pub fn checksum(&self) -> u8 {
+        fn twos_complement(num: u16) -> u16 {
+            (num ^ u16::MAX).wrapping_add(1)
+        }
+
+        let mut ret: u16 = 0;
+
+        ret = ret.wrapping_add(self.data.len() as u16);
+        ret = ret.wrapping_add(self.addr);
+        ret = ret.wrapping_add(self.addr >> 8);
+        ret = ret.wrapping_add(self.typ as u16);
+        for &byte in &self.data {
+            ret = ret.wrapping_add(byte as u16)
+        }
+
+        (0xff & twos_complement(ret)) as u8
+    }
+}
+
+impl Display for HexRecord {
+    fn fmt(&self, f: &mut fmt::Formatter)-> fmt::Result {
+        write!(f, ":{:02x}{:04x}{:02x}",
+               self.data.len() & 0xff,
+               self.addr,
+               self.typ as u8)?;
+        for byte in &self.data {
+            write!(f, "{:02x}", *byte)?;
+        }
+        write!(f, "{:02x}", self.checksum())
+    }
+}
+
+/// a line that isnt a valid record
+#[derive(Debug)]
+pub enum HexParseError {
+    /// no leading colon
+    MissingStartCode,
+    /// odd number of digits, or a digit thats not hex
+    InvalidHex,
+    /// less than a count, address, type, and checksum
+    TooShort,
+    /// count doesnt match the data
+    ByteCountMismatch {
+        /// the count in the record
+        expected: usize,
+        /// data bytes in the record
+        got: usize,
+    },
+    /// checksum doesnt match
+    ChecksumMismatch {
+        /// checksum of the record
+        expected: u8,
+        /// checksum it has
+        got: u8,
+    },
+    /// type thats not 00 to 05
+    UnknownRecordType(u8),
+    /// wrong data length for an address or end of file record
+    InvalidDataLength(HexRecordType, usize),
+}
+
+impl Display for HexParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use HexParseError::*;
+
+        match self {
+            MissingStartCode => write!(f, "record doesnt start with ':'"),
+            InvalidHex => write!(f, "record isnt valid hex"),
+            TooShort => write!(f, "record is too short"),
+            ByteCountMismatch { expected, got } => {
+                write!(f, "byte count is {} but record has {} data bytes", expected, got)
+            }
+            ChecksumMismatch { expected, got } => {
+                write!(f, "checksum is {:02x}, expected {:02x}", got, expected)
+            }
+            UnknownRecordType(typ) => write!(f, "unknown record type {:02x}", typ),
+            InvalidDataLength(typ, len) => {
+                write!(f, "{:?} record cant have {} data bytes", typ, len)
+            }
+        }
+    }
+}
+
+impl FromStr for HexRecord {
+    type Err = HexParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let digits = line.strip_prefix(':')
+                         .ok_or(HexParseError::MissingStartCode)?;
+
+        if digits.len() % 2 != 0 || !digits.is_ascii() {
+            return Err(HexParseError::InvalidHex);
+        }
+
+        let bytes: Vec<u8> = (0..digits.len()).step_by(2)
+                                              .map(| i | u8::from_str_radix(&digits[i..i + 2], 16))
+                                              .collect::<Result<_, _>>()
+                                              .map_err(|_| HexParseError::InvalidHex)?;
+
+        // count, addr, type, checksum
+        if bytes.len() < 5 {
+            return Err(HexParseError::TooShort);
+        }
+
+        let count = bytes[0] as usize;
+        let data = &bytes[4..bytes.len() - 1];
+        if count != data.len() {
+            return Err(HexParseError::ByteCountMismatch {
+                expected: count,
+                got: data.len(),
+            });
+        }
+
+        let typ = HexRecordType::from_u8(bytes[3])
+                                .ok_or(HexParseError::UnknownRecordType(bytes[3]))?;
+        if let Some(len) = typ.data_len() {
+            if len != data.len() {
+                return Err(HexParseError::InvalidDataLength(typ, data.len()));
+            }
+        }
+
+        let record = HexRecord {
+            typ,
+            addr: u16::from_be_bytes([bytes[1], bytes[2]]),
+            data: data.to_vec(),
+        };
+
+        let checksum = bytes[bytes.len() - 1];
+        if checksum != record.checksum() {
+            return Err(HexParseError::ChecksumMismatch {
+                expected: record.checksum(),
+                got: checksum,
+            });
+        }
+
+        Ok(record)
+    }
+}
+
+/// a file that isnt valid intel hex
+#[derive(Debug)]
+pub enum HexReadError {
+    /// line number from 1, and the error in it
+    Line(usize, HexParseError),
+    /// no 01 record
+    MissingEndOfFile,
+}
+
+impl Display for HexReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HexReadError::Line(line, err) => write!(f, "line {}: {}", line, err),
+            HexReadError::MissingEndOfFile => write!(f, "missing end of file record"),
+        }
+    }
+}
+
+/// reads records up to and including the end of file record
+pub fn read_hex_records(text: &str) -> Result<Vec<HexRecord>, HexReadError> {
+    let mut records = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record: HexRecord = line.parse()
+                                    .map_err(| err | HexReadError::Line(i + 1, err))?;
+        let is_eof = matches!(record.typ, HexRecordType::EndOfFile);
+        records.push(record);
+        if is_eof {
+            return Ok(records);
+        }
+    }
+
+    Err(HexReadError::MissingEndOfFile)
+}
+
+/// byte address from a start record, if there is one
+pub fn start_address(records: &[HexRecord]) -> Option<u64> {
+    records.iter()
+           .find_map(| record | {
+               let word = | at: usize | u16::from_be_bytes([record.data[at], record.data[at + 1]]) as u64;
+               match record.typ {
+                   HexRecordType::StartSegmentAddress => Some((word(0) << 4) + word(2)),
+                   HexRecordType::StartLinearAddress  => Some((word(0) << 16) | word(2)),
+                   _ => None,
+               }
+           })
+}
+
+/// contiguous runs of data from records, at byte addresses
+pub fn hex_regions(records: &[HexRecord]) -> Vec<(u64, Vec<u8>)> {
+    use HexRecordType::*;
+
+    let mut regions: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut upper = 0;
+
+    for record in records {
+        match record.typ {
+            Data => {
+                let addr = upper + record.addr as u64;
+                match regions.last_mut() {
+                    Some((start, data)) if *start + data.len() as u64 == addr => {
+                        data.extend_from_slice(&record.data);
+                    }
+                    _ => regions.push((addr, record.data.clone())),
+                }
+            }
+            ExtendedSegmentAddress => {
+                upper = (u16::from_be_bytes([record.data[0], record.data[1]]) as u64) << 4;
+            }
+            ExtendedLinearAddress => {
+                upper = (u16::from_be_bytes([record.data[0], record.data[1]]) as u64) << 16;
+            }
+            _ => {}
+        }
+    }
+
+    regions
+}
+
+//
+
+/// which extended address records can be written
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HexFormat {
+    /// 16 bit addresses only
+    I8,
+    /// extended segment address records, up to 1M
+    I16,
+    /// extended linear address records, up to 4G
+    I32,
+}
+
+#[derive(Debug)]
+pub(crate) enum HexObject {
+    AddressTag(u64),
+    Opcode {
+        value: u64,
+        size: usize,
+    },
+    Data(Vec<u8>),
+}
+
+// addr is in bytes
+//   records never cross a 64k boundary
+//   upper is the last upper address written by an extended address record
+pub(crate) fn push_data_records(
+    records: &mut Vec<HexRecord>,
+    upper: &mut u64,
+    mut addr: u64,
+    mut data: &[u8],
+    settings: &CompileSettings
+) -> Result<(), CompileError> {
+    let bytes_per_record = settings.words_per_record as usize * settings.opcode_size as usize;
+
+    while !data.is_empty() {
+        let room = (0x10000 - (addr & 0xffff)) as usize;
+        let take = bytes_per_record.min(room)
+                                   .min(data.len());
+
+        let addr_upper = addr >> 16;
+        if addr_upper != *upper {
+            let record =
+                match settings.hex_format {
+                    HexFormat::I8 => None,
+                    HexFormat::I16 if addr_upper <= 0xf => {
+                        Some(HexRecord {
+                            typ: HexRecordType::ExtendedSegmentAddress,
+                            addr: 0,
+                            data: ((addr_upper << 12) as u16).to_be_bytes().to_vec(),
+                        })
+                    }
+                    HexFormat::I16 => None,
+                    HexFormat::I32 if addr_upper <= 0xffff => {
+                        Some(HexRecord {
+                            typ: HexRecordType::ExtendedLinearAddress,
+                            addr: 0,
+                            data: (addr_upper as u16).to_be_bytes().to_vec(),
+                        })
+                    }
+                    HexFormat::I32 => None,
+                };
+            let record = record.ok_or(CompileError::HexAddressOutOfRange(addr, settings.hex_format))?;
+            records.push(record);
+            *upper = addr_upper;
+        }
+
+        records.push(HexRecord {
+            typ: HexRecordType::Data,
+            addr: (addr & 0xffff) as u16,
+            data: data[..take].to_vec(),
+        });
+
+        addr += take as u64;
+        data = &data[take..];
+    }
+
+    Ok(())
+}
+
+// addr is in bytes
+//   I8HEX has no start record
+//   a start segment record is CS:IP, with CS the same as in extended segment records
+fn start_record(addr: u64, format: HexFormat) -> Result<HexRecord, CompileError> {
+    let data =
+        match format {
+            HexFormat::I8 => return Err(CompileError::StartAddressUnsupported(format)),
+            HexFormat::I16 if addr <= 0xfffff => {
+                let cs = ((addr >> 16) << 12) as u16;
+                let ip = (addr & 0xffff) as u16;
+                [cs.to_be_bytes(), ip.to_be_bytes()].concat()
+            }
+            HexFormat::I32 if addr <= 0xffff_ffff => (addr as u32).to_be_bytes().to_vec(),
+            _ => return Err(CompileError::HexAddressOutOfRange(addr, format)),
+        };
+
+    let typ =
+        if format == HexFormat::I16 {
+            HexRecordType::StartSegmentAddress
+        } else {
+            HexRecordType::StartLinearAddress
+        };
+
+    Ok(HexRecord { typ, addr: 0, data })
+}
+
+// entry is the byte address for a start record
+//   records are in address order, however the source was laid out
+pub(crate) fn generate_hex_records(
+    image: &MemoryImage,
+    entry: Option<u64>,
+    settings: &CompileSettings
+) -> Result<Vec<HexRecord>, CompileError> {
+    let mut records = Vec::new();
+    let mut upper = 0;
+
+    for (addr, bytes) in image.regions() {
+        push_data_records(&mut records, &mut upper, addr, bytes, settings)?;
+    }
+
+    if let Some(addr) = entry {
+        records.push(start_record(addr, settings.hex_format)?);
+    }
+
+    records.push(HexRecord {
+        typ: HexRecordType::EndOfFile,
+        addr: 0,
+        data: Vec::new(),
+    });
+
+    Ok(records)
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+
+    fn parse(line: &str) -> HexParseError {
+        line.parse::<HexRecord>().unwrap_err()
+    }
+
+    fn round_trip(format: HexFormat, regions: &[(u64, Vec<u8>)], entry: Option<u64>) {
+        let mut settings = avr::compile_settings();
+        settings.hex_format = format;
+        let image = MemoryImage::from_regions(regions).unwrap();
+
+        let text: String = generate_hex_records(&image, entry, &settings).unwrap()
+                                                                         .iter()
+                                                                         .map(| record | format!("{}\n", record))
+                                                                         .collect();
+        let records = read_hex_records(&text).unwrap();
+
+        assert_eq!(hex_regions(&records), image.to_regions());
+        assert_eq!(start_address(&records), entry);
+        let written: Vec<String> = records.iter()
+                                          .map(| record | record.to_string())
+                                          .collect();
+        assert_eq!(written.join("\n") + "\n", text);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        round_trip(HexFormat::I8, &[(0, vec![0xff, 0xcf]), (0x100, bytes.clone())], None);
+        // across a 64k boundary, and past it
+        round_trip(HexFormat::I16, &[(0xfff0, bytes.clone()), (0x2_0000, vec![1])], Some(0x1_0004));
+        round_trip(HexFormat::I32, &[(0xfff0, bytes), (0x82_0000, vec![0x62, 0xd9, 0xff])], Some(0x1234));
+    }
+
+    #[test]
+    fn reject_records() {
+        assert!(matches!(parse("00000001ff"), HexParseError::MissingStartCode));
+        assert!(matches!(parse(":00000001f"), HexParseError::InvalidHex));
+        assert!(matches!(parse(":0000000zff"), HexParseError::InvalidHex));
+        assert!(matches!(parse(":00000001"), HexParseError::TooShort));
+        assert!(matches!(parse(":0200000001ff"), HexParseError::ByteCountMismatch { expected: 2, got: 1 }));
+        assert!(matches!(parse(":00000001fe"), HexParseError::ChecksumMismatch { expected: 0xff, got: 0xfe }));
+        assert!(matches!(parse(":00000006fa"), HexParseError::UnknownRecordType(6)));
+        assert!(matches!(parse(":0100000100fe"), HexParseError::InvalidDataLength(HexRecordType::EndOfFile, 1)));
+    }
+
+    #[test]
+    fn read_errors() {
+        // lines count from 1, blank ones too
+        let err = read_hex_records(":0100000000ff\n\n:00000001fe\n").unwrap_err();
+        assert!(matches!(err, HexReadError::Line(3, HexParseError::ChecksumMismatch { .. })));
+        assert_eq!(err.to_string(), "line 3: checksum is fe, expected ff");
+
+        let err = read_hex_records(":0100000000ff\n").unwrap_err();
+        assert!(matches!(err, HexReadError::MissingEndOfFile));
+
+        // nothing after the end of file record is read
+        let records = read_hex_records(":00000001ff\nnot hex\n").unwrap();
+        assert_eq!(records.len(), 1);
+    }
+}
@@ -0,0 +1,259 @@
+//! sparse memory images, what every writer writes from
+
+use std::collections::BTreeMap;
+
+use crate::compile::{CompileSettings, Section};
+use crate::errors::CompileError;
+use crate::hexfmt::HexObject;
+
+/// bytes at byte addresses
+///   regions are kept sorted, and touching ones are joined unless a section starts between them
+///   writing a byte twice is an error
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryImage {
+    regions: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MemoryImage {
+    /// no bytes
+    pub fn new() -> Self {
+        MemoryImage::default()
+    }
+
+    /// regions can come in any order, but cant overlap
+    pub fn from_regions(regions: &[(u64, Vec<u8>)]) -> Result<Self, CompileError> {
+        let mut image = MemoryImage::new();
+        for (addr, bytes) in regions {
+            image.write(*addr, bytes)?;
+        }
+        Ok(image)
+    }
+
+    // hex objects are in code order, each run starting at an AddressTag
+    pub(crate) fn from_hex_objects(hex_objects: &[HexObject], settings: &CompileSettings) -> Result<Self, CompileError> {
+        let mut image = MemoryImage::new();
+        let mut addr = 0;
+
+        for obj in hex_objects {
+            let bytes =
+                match obj {
+                    HexObject::AddressTag(tag_addr) => {
+                        addr = *tag_addr;
+                        continue;
+                    }
+                    HexObject::Opcode { value, size } => settings.opcode_bytes(*value, *size),
+                    HexObject::Data(bytes)            => bytes.clone(),
+                };
+            image.write(addr, &bytes)?;
+            addr += bytes.len() as u64;
+        }
+
+        Ok(image)
+    }
+
+    /// if no bytes are written
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// bytes written, not counting gaps
+    pub fn len(&self) -> usize {
+        self.regions.values()
+                    .map(| bytes | bytes.len())
+                    .sum()
+    }
+
+    /// lowest address and one past the highest
+    pub fn span(&self) -> Option<(u64, u64)> {
+        let (&start, _) = self.regions.iter().next()?;
+        let (&last, bytes) = self.regions.iter().next_back()?;
+        Some((start, last + bytes.len() as u64))
+    }
+
+    /// first address in start..end thats already written
+    pub fn overlap(&self, start: u64, end: u64) -> Option<u64> {
+        if let Some((&addr, bytes)) = self.regions.range(..=start).next_back() {
+            if addr + bytes.len() as u64 > start {
+                return Some(start);
+            }
+        }
+        self.regions.range(start..end)
+                    .next()
+                    .map(| (&addr, _) | addr)
+    }
+
+    /// errors if any byte is already written
+    pub fn write(&mut self, addr: u64, bytes: &[u8]) -> Result<(), CompileError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        let end = addr + bytes.len() as u64;
+        if let Some(at) = self.overlap(addr, end) {
+            return Err(CompileError::ImageOverlap(at));
+        }
+
+        // split at section bases, so section() can go by where a region starts
+        let (mut addr, mut bytes) = (addr, bytes);
+        while !bytes.is_empty() {
+            let take = Section::ALL.iter()
+                                   .map(| section | section.base())
+                                   .find(| &base | base > addr)
+                                   .map_or(bytes.len(), | base | bytes.len().min((base - addr) as usize));
+            self.insert(addr, &bytes[..take]);
+            addr += take as u64;
+            bytes = &bytes[take..];
+        }
+
+        Ok(())
+    }
+
+    // joins the region ending at addr and the one starting at the end
+    //   unless thats across a section base
+    fn insert(&mut self, addr: u64, bytes: &[u8]) {
+        let is_base = | addr | Section::ALL.iter().any(| section | section.base() == addr);
+        let end = addr + bytes.len() as u64;
+
+        let before = self.regions.range(..addr)
+                                 .next_back()
+                                 .filter(| (&start, data) | start + data.len() as u64 == addr && !is_base(addr))
+                                 .map(| (&start, _) | start);
+        let (start, mut data) =
+            match before {
+                Some(start) => (start, self.regions.remove(&start).unwrap()),
+                None => (addr, Vec::with_capacity(bytes.len())),
+            };
+        data.extend_from_slice(bytes);
+        if !is_base(end) {
+            if let Some(after) = self.regions.remove(&end) {
+                data.extend(after);
+            }
+        }
+        self.regions.insert(start, data);
+    }
+
+    /// errors if the images share any byte
+    pub fn merge(&mut self, other: &MemoryImage) -> Result<(), CompileError> {
+        for (addr, bytes) in other.regions() {
+            self.write(addr, bytes)?;
+        }
+        Ok(())
+    }
+
+    /// one byte, if its written
+    pub fn get(&self, addr: u64) -> Option<u8> {
+        self.read(addr, 1).map(| bytes | bytes[0])
+    }
+
+    /// None unless every byte in addr..addr + len is written
+    pub fn read(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let (&start, bytes) = self.regions.range(..=addr).next_back()?;
+        let at = (addr - start) as usize;
+        bytes.get(at..at + len)
+    }
+
+    /// one word at a byte address, in the settings byte order
+    pub fn read_word(&self, addr: u64, settings: &CompileSettings) -> Option<u64> {
+        self.read(addr, settings.opcode_size as usize)
+            .map(| bytes | settings.word_from_bytes(bytes))
+    }
+
+    /// sorted, and no two touch but at a section base
+    pub fn regions(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.regions.iter()
+                    .map(| (&addr, bytes) | (addr, bytes.as_slice()))
+    }
+
+    /// like regions, but copied
+    pub fn to_regions(&self) -> Vec<(u64, Vec<u8>)> {
+        self.regions()
+            .map(| (addr, bytes) | (addr, bytes.to_vec()))
+            .collect()
+    }
+
+    /// the part in one section, moved to address 0
+    pub fn section(&self, section: Section) -> MemoryImage {
+        let regions = self.regions.iter()
+                                  .filter(| (&addr, _) | Section::from_addr(addr) == section)
+                                  .map(| (&addr, bytes) | (addr - section.base(), bytes.clone()))
+                                  .collect();
+        MemoryImage { regions }
+    }
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+
+    #[test]
+    fn writes_join_and_overlaps_fail() {
+        let mut image = MemoryImage::new();
+        assert!(image.is_empty());
+        assert_eq!(image.span(), None);
+
+        image.write(4, &[4, 5]).unwrap();
+        image.write(0, &[0, 1]).unwrap();
+        // fills the gap, so all three are one region
+        image.write(2, &[2, 3]).unwrap();
+        image.write(10, &[10]).unwrap();
+        image.write(8, &[]).unwrap();
+        assert_eq!(image.to_regions(), [(0, vec![0, 1, 2, 3, 4, 5]), (10, vec![10])]);
+        assert_eq!(image.len(), 7);
+        assert_eq!(image.span(), Some((0, 11)));
+
+        // reports the first byte written twice, and changes nothing
+        assert!(matches!(image.write(9, &[9, 9]), Err(CompileError::ImageOverlap(10))));
+        assert!(matches!(image.write(5, &[5]), Err(CompileError::ImageOverlap(5))));
+        assert_eq!(image.overlap(6, 10), None);
+        assert_eq!(image.overlap(3, 12), Some(3));
+        assert_eq!(image.len(), 7);
+    }
+
+    #[test]
+    fn reads_need_every_byte() {
+        let image = MemoryImage::from_regions(&[(0x10, vec![0x0f, 0xef]), (0x20, vec![1])]).unwrap();
+        assert_eq!(image.get(0x11), Some(0xef));
+        assert_eq!(image.get(0x12), None);
+        assert_eq!(image.get(0x0f), None);
+        assert_eq!(image.read(0x10, 2), Some(&[0x0f, 0xef][..]));
+        assert_eq!(image.read(0x11, 2), None);
+        assert_eq!(image.read_word(0x10, &avr::compile_settings()), Some(0xef0f));
+        assert_eq!(image.read_word(0x20, &avr::compile_settings()), None);
+    }
+
+    #[test]
+    fn merges_dont_overlap() {
+        let mut image = MemoryImage::from_regions(&[(0, vec![1, 2])]).unwrap();
+        image.merge(&MemoryImage::from_regions(&[(2, vec![3]), (8, vec![4])]).unwrap()).unwrap();
+        assert_eq!(image.to_regions(), [(0, vec![1, 2, 3]), (8, vec![4])]);
+
+        let other = MemoryImage::from_regions(&[(8, vec![5])]).unwrap();
+        assert!(matches!(image.merge(&other), Err(CompileError::ImageOverlap(8))));
+        assert!(MemoryImage::from_regions(&[(0, vec![1, 2]), (1, vec![3])]).is_err());
+    }
+
+    #[test]
+    fn regions_split_at_section_bases() {
+        let data = Section::Data.base();
+        let eeprom = Section::Eeprom.base();
+
+        // one write across a base, and two that touch at one
+        let mut image = MemoryImage::new();
+        image.write(data - 2, &[1, 2, 3, 4]).unwrap();
+        image.write(eeprom - 1, &[5]).unwrap();
+        image.write(eeprom, &[6]).unwrap();
+        assert_eq!(image.to_regions(), [
+            (data - 2, vec![1, 2]),
+            (data, vec![3, 4]),
+            (eeprom - 1, vec![5]),
+            (eeprom, vec![6]),
+        ]);
+
+        assert_eq!(image.section(Section::Text).to_regions(), [(data - 2, vec![1, 2])]);
+        assert_eq!(image.section(Section::Data).to_regions(), [(0, vec![3, 4]), (0xffff, vec![5])]);
+        assert_eq!(image.section(Section::Eeprom).to_regions(), [(0, vec![6])]);
+        assert!(image.section(Section::Fuse).is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+//! instruction definitions
+//!
+//! an [`Opdef`] is built from a bit pattern like `"000011rdddddrrrr"`,
+//! one letter per operand field
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use crate::encode::Bits;
+use crate::errors::CompileError;
+
+/// how an operand is read and resolved
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ArgKind {
+    /// rN, the number before its transform
+    Register,
+    /// a number
+    Immediate,
+    /// a data or io address
+    Address,
+    /// a program address, so only text labels
+    Absolute,
+    /// a program address, encoded as words from the next instruction
+    Relative,
+    /// a pointer register like X or Z+, named in source and never encoded
+    Pointer(&'static str),
+}
+
+/// applied to an operand before its bits are scattered into the opcode
+#[derive(Clone, Debug)]
+pub enum Transform {
+    /// as is
+    None,
+    /// minus the base, like r16 to r31 as 0 to 15
+    Subtract(i64),
+    /// divided, and has to divide evenly
+    Divide(i64),
+    /// a signed field this many bits wide
+    TwosComplement(usize),
+    /// inverted in a field this many bits wide
+    Complement(usize),
+    /// the index of the value in the table
+    Lookup(Vec<i64>),
+}
+
+impl Transform {
+    /// None if val cant be encoded at all
+    ///   the result still has to be checked against the field width
+    pub fn apply(&self, val: i64) -> Option<u64> {
+        match self {
+            Transform::None           => u64::try_from(val).ok(),
+            Transform::Subtract(base) => u64::try_from(val - base).ok(),
+            Transform::Divide(by)     => {
+                if val % by == 0 {
+                    u64::try_from(val / by).ok()
+                } else {
+                    None
+                }
+            }
+            Transform::TwosComplement(n) => {
+                let (min, max) = self.range(*n);
+                if val >= min && val <= max {
+                    Some(val as u64 & u64::mask(*n))
+                } else {
+                    None
+                }
+            }
+            Transform::Complement(n) => {
+                // checked before the complement hides the overflow
+                u64::try_from(val).ok()
+                                  .filter(| &val | val <= u64::mask(*n))
+                                  .map(| val | !val & u64::mask(*n))
+            }
+            Transform::Lookup(table) => {
+                table.iter()
+                     .position(| &entry | entry == val)
+                     .map(| at | at as u64)
+            }
+        }
+    }
+
+    /// inverse of apply
+    pub fn unapply(&self, encoded: u64) -> i64 {
+        match self {
+            Transform::None              => encoded as i64,
+            Transform::Subtract(base)    => encoded as i64 + base,
+            Transform::Divide(by)        => encoded as i64 * by,
+            Transform::TwosComplement(n) => {
+                let sign = 1 << (n - 1);
+                (encoded as i64 ^ sign) - sign
+            }
+            Transform::Complement(n)     => (!encoded & u64::mask(*n)) as i64,
+            Transform::Lookup(table)     => {
+                table.get(encoded as usize)
+                     .copied()
+                     .unwrap_or(encoded as i64)
+            }
+        }
+    }
+
+    /// range of values that fit in a field bits wide, before the transform
+    pub fn range(&self, bits: usize) -> (i64, i64) {
+        let max = u64::mask(bits) as i64;
+        match self {
+            Transform::None              => (0, max),
+            Transform::Subtract(base)    => (*base, base + max),
+            Transform::Divide(by)        => (0, by * max),
+            Transform::TwosComplement(n) => (-(1 << (n - 1)), (1 << (n - 1)) - 1),
+            Transform::Complement(n)     => (0, u64::mask(*n) as i64),
+            Transform::Lookup(table)     => {
+                (*table.iter().min().unwrap_or(&0),
+                 *table.iter().max().unwrap_or(&0))
+            }
+        }
+    }
+}
+
+/// one operand
+///   masks are the opcode bits its scattered into, more than one ties fields together
+#[derive(Clone, Debug)]
+pub struct OpArg {
+    /// how the operand is read
+    pub kind: ArgKind,
+    /// one per field, usually just one
+    pub masks: Vec<u64>,
+    /// applied before scattering
+    pub transform: Transform,
+}
+
+impl OpArg {
+    /// width of the field
+    pub fn bits(&self) -> usize {
+        self.masks[0].count_ones() as usize
+    }
+
+    /// None if val doesnt fit the field
+    pub fn encode(&self, val: i64) -> Option<u64> {
+        self.transform.apply(val)
+                      .filter(| &encoded | encoded <= u64::mask(self.bits()))
+    }
+
+    /// None if tied fields dont agree
+    pub fn decode(&self, opcode: u64) -> Option<i64> {
+        let encoded = self.masks[0].spit(opcode);
+        if self.masks[1..].iter().all(| mask | mask.spit(opcode) == encoded) {
+            Some(self.transform.unapply(encoded))
+        } else {
+            None
+        }
+    }
+}
+
+/// one form of an instruction
+#[derive(Debug)]
+pub struct Opdef {
+    /// mnemonic, shared by every form
+    pub name: String,
+    /// opcode with every operand 0
+    pub base: u64,
+    /// in source order
+    pub args: Vec<OpArg>,
+    /// opcode width
+    pub bits: usize,
+}
+
+impl Opdef {
+    /// arg_order has one letter per operand
+    ///   'a=b' writes the same operand to both the a and b fields
+    ///   a letter that isnt in spec_str gives an operand with no bits, ie a pointer
+    pub fn new(name: &str, spec_str: &str, arg_order: &str, kinds: &[ArgKind]) -> Self {
+        let spec_bytes = spec_str.as_bytes();
+
+        let base = spec_bytes.iter()
+                             .map(| &byte | byte == b'1')
+                             .fold(0, | acc, tf | {
+                                 if tf {
+                                     (acc << 1) | 1
+                                 } else {
+                                     acc << 1
+                                 }
+                             });
+
+        let field_mask = | arg_byte: u8 | {
+            spec_bytes.iter()
+                      .map(| &spec_byte | arg_byte == spec_byte)
+                      .fold(0, | acc, tf | {
+                          if tf {
+                              (acc << 1) | 1
+                          } else {
+                              acc << 1
+                          }
+                      })
+        };
+
+        let mut arg_masks: Vec<Vec<u64>> = Vec::new();
+        let mut arg_bytes = arg_order.bytes();
+        while let Some(arg_byte) = arg_bytes.next() {
+            if arg_byte == b'=' {
+                let tied = arg_bytes.next().unwrap();
+                arg_masks.last_mut().unwrap().push(field_mask(tied));
+            } else {
+                arg_masks.push(vec![field_mask(arg_byte)]);
+            }
+        }
+
+        assert_eq!(arg_masks.len(), kinds.len(), "opdef {}: arg_order and kinds differ in length", name);
+
+        let args = arg_masks.into_iter()
+                            .zip(kinds.iter())
+                            .map(| (masks, &kind) | {
+                                OpArg {
+                                    kind,
+                                    masks,
+                                    transform: Transform::None,
+                                }
+                            })
+                            .collect();
+
+        Opdef {
+            name: name.to_string(),
+            base,
+            args,
+            bits: spec_bytes.len(),
+        }
+    }
+
+    /// sets the transform of operand arg, in source order
+    pub fn with_transform(mut self, arg: usize, transform: Transform) -> Self {
+        self.args[arg].transform = transform;
+        self
+    }
+
+    /// bits that arent part of any operand
+    pub fn fixed_mask(&self) -> u64 {
+        self.args.iter()
+                 .flat_map(| arg | arg.masks.iter())
+                 .fold(u64::mask(self.bits), | acc, &mask | acc & !mask)
+    }
+
+    /// inverse of apply
+    ///   None if opcode isnt an encoding of this opdef
+    pub fn unapply(&self, opcode: u64) -> Option<Vec<i64>> {
+        if opcode & self.fixed_mask() != self.base {
+            return None;
+        }
+        self.args.iter()
+                 .map(| arg | arg.decode(opcode))
+                 .collect()
+    }
+
+    /// words taken up by this instruction
+    pub fn size(&self, word_bits: usize) -> usize {
+        self.bits.div_ceil(word_bits)
+    }
+
+    /// the opcode for the operands, in source order
+    pub fn apply(&self, arg_vals: &[i64]) -> Result<u64, CompileError> {
+        if arg_vals.len() != self.args.len() {
+            return Err(CompileError::OperandCount {
+                instruction: self.name.clone(),
+                expected: self.args.len(),
+                got: arg_vals.len(),
+            });
+        }
+
+        let mut ret = self.base;
+        for (i, (arg, &val)) in self.args.iter().zip(arg_vals.iter()).enumerate() {
+            let encoded = arg.encode(val).ok_or_else(|| {
+                let (min, max) = arg.transform.range(arg.bits());
+                CompileError::OperandOutOfRange {
+                    instruction: self.name.clone(),
+                    operand: i,
+                    value: val,
+                    min,
+                    max,
+                }
+            })?;
+            for &mask in &arg.masks {
+                ret |= mask.eat(encoded);
+            }
+        }
+        Ok(ret)
+    }
+}
+
+//
+
+/// owns its opdefs so code objects can outlive it
+///   opdefs with the same name are forms of one instruction, tried in order
+#[derive(Clone, Debug, Default)]
+pub struct Isa {
+    forms: HashMap<String, Vec<Arc<Opdef>>>,
+}
+
+impl Isa {
+    /// one form per opdef
+    pub fn new(opdefs: Vec<Opdef>) -> Self {
+        let mut isa = Isa::default();
+        for opdef in opdefs {
+            isa.add(opdef);
+        }
+        isa
+    }
+
+    /// another form, tried after the ones already there
+    pub fn add(&mut self, opdef: Opdef) {
+        self.forms.entry(opdef.name.clone())
+                  .or_default()
+                  .push(Arc::new(opdef));
+    }
+
+    /// forms of an instruction, in the order they were added
+    pub fn get(&self, name: &str) -> Option<&[Arc<Opdef>]> {
+        self.forms.get(name)
+                  .map(| forms | forms.as_slice())
+    }
+
+    /// every form of every instruction
+    pub fn opdefs(&self) -> impl Iterator<Item = &Arc<Opdef>> {
+        self.forms.values()
+                  .flatten()
+    }
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_needs_every_operand() {
+        let ldi = Opdef::new("ldi", "1110KKKKddddKKKK", "dK", &[ArgKind::Register, ArgKind::Immediate])
+                        .with_transform(0, Transform::Subtract(16));
+        assert_eq!(ldi.apply(&[16, 0xff]).unwrap(), 0xef0f);
+        assert!(matches!(ldi.apply(&[16]), Err(CompileError::OperandCount { expected: 2, got: 1, .. })));
+        assert!(matches!(ldi.apply(&[16, 1, 2]), Err(CompileError::OperandCount { expected: 2, got: 3, .. })));
+        assert!(matches!(ldi.apply(&[15, 1]), Err(CompileError::OperandOutOfRange { operand: 0, min: 16, max: 31, .. })));
+    }
+}
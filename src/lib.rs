@@ -0,0 +1,41 @@
+//! an assembler that reads its source as s-expressions
+//!
+//! ```
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let source = "(org 0) (label loop) (rjmp loop)";
+//! let isa = uokichi::avr::instruction_set();
+//! let code = uokichi::frontend::read_program(source, &isa)?;
+//! let records = uokichi::compile(&uokichi::avr::compile_settings(), &code)?;
+//! assert_eq!(records[0].to_string(), ":02000000ffcf30");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! `isa` describes instructions, `frontend` reads source into code objects,
+//! `compile` resolves symbols and lays them out in a `MemoryImage`,
+//! and `hexfmt`, `srec`, `binary`, and `elf` write the output
+
+#![warn(missing_docs)]
+
+pub mod avr;
+pub mod binary;
+pub mod compile;
+pub mod device;
+pub mod disasm;
+pub mod elf;
+pub mod encode;
+pub mod errors;
+pub mod frontend;
+pub mod hexfmt;
+pub mod image;
+pub mod isa;
+pub mod reader;
+pub mod srec;
+
+pub use compile::{compile, compile_bin, compile_elf, compile_image, compile_srec, symbol_table, CodeObject, CompileSettings};
+pub use device::Device;
+pub use errors::{CompileError, SourceError};
+pub use hexfmt::HexRecord;
+pub use image::MemoryImage;
+pub use isa::{Isa, Opdef};
+pub use srec::SRecord;
@@ -1,423 +1,365 @@
-#![allow(dead_code)]
-
-use std::collections::HashMap;
+use std::env;
 use std::fmt;
-use std::fmt::{Debug, Display};
-use std::mem;
-use num::{PrimInt, ToPrimitive, Unsigned};
-
-trait Bits: Debug + PrimInt + Unsigned {
-    // has to be unsigned
-    //   1. using them as bytes anyway
-    //   2. max_value is all ones
-
-    fn from_u64(val: u64) -> Self {
-       Self::from(val & Self::max_value().to_u64().unwrap()).unwrap()
-    }
-
-    fn mask(ct: usize) -> Self {
-        !(Self::max_value() << ct)
-    }
-
-    fn is_bit_set(self, at: usize) -> bool {
-        self >> at & Self::one() == Self::one()
-    }
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+use uokichi::compile::{Section, Symbol, SymbolKind};
+use uokichi::reader::Position;
+use uokichi::binary::flat_image;
+use uokichi::hexfmt::hex_regions;
+use uokichi::srec::srec_regions;
+use uokichi::{avr, device, disasm, frontend};
+use uokichi::{CodeObject, CompileError, CompileSettings, Isa, SourceError};
+
+const USAGE: &str = "\
+usage: uokichi <input> [options]
+
+options:
+  -o <file>          output file, defaults to the input with an extension for the format
+  --device <name>    target device, defaults to avr, which checks no sizes
+                     attiny13, attiny85, atmega8, atmega168, atmega328p,
+                     atmega32u4, atmega2560, or atxmega32a4
+  --format <format>  output format: ihex, srec, bin, or elf
+  --listing <file>   write a disassembly of the output
+  --map <file>       write the symbol table
+  --entry <label>    write a start address record for label
+  --section <name>   section to write: text, data, eeprom, fuse, or all, defaults to text
+  --fill <byte>      byte between regions in a bin, defaults to 0xff
+  --split            with bin, write one file per region named like out-001f00.bin
+  -h, --help         print this and exit
+
+exit codes:
+  0  success
+  1  error in the source
+  2  bad usage
+  3  couldnt read or write a file
+";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum OutputFormat {
+    IHex,
+    SRec,
+    Bin,
+    Elf,
+}
 
-    fn eat(mut self, mut val: Self) -> Self {
-        let bit_sz = mem::size_of::<Self>() * 8;
-        let mut ret = Self::zero();
-        for i in 0..bit_sz {
-            if self & Self::one() == Self::one() {
-                ret = ret | ((val & Self::one()) << i);
-                val = val >> 1;
-            }
-            self = self >> 1;
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::IHex => "hex",
+            OutputFormat::SRec => "srec",
+            OutputFormat::Bin  => "bin",
+            OutputFormat::Elf  => "elf",
         }
-        ret
     }
+}
 
-    fn to_bytes(mut self, ct: usize) -> Vec<u8>
-    where
-        Self: ToPrimitive {
-        let mut ret = vec![0u8; ct];
-        for i in (0..ct).rev() {
-            ret[i] = (self & Self::from(0xff).unwrap()).to_u8().unwrap();
-            self = self >> 8;
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ihex" => Ok(OutputFormat::IHex),
+            "srec" => Ok(OutputFormat::SRec),
+            "bin"  => Ok(OutputFormat::Bin),
+            "elf"  => Ok(OutputFormat::Elf),
+            _ => Err(()),
         }
-        ret
     }
 }
 
-// impl Bits for u8 {}
-// impl Bits for u16 {}
-// impl Bits for u32 {}
-impl Bits for u64 {}
-
-//
+#[derive(Debug)]
+struct Options {
+    input: String,
+    output: Option<String>,
+    device: String,
+    format: OutputFormat,
+    listing: Option<String>,
+    map: Option<String>,
+    entry: Option<String>,
+    fill: Option<u8>,
+    split: bool,
+    section: Option<String>,
+}
 
 #[derive(Debug)]
-struct Opdef {
-    name: String,
-    base: u64,
-    args: Vec<u64>,
-    shift: i32,
+enum CliError {
+    Help,
+    Usage(String),
+    Io(String, io::Error),
+    Source(String, SourceError),
+    Compile(String, CompileError),
 }
 
-impl Opdef {
-    fn new(name: &str, spec_str: &str, arg_order: &str, shift: i32) -> Self {
-        let spec_bytes = spec_str.as_bytes();
-        let arg_bytes = arg_order.as_bytes();
-
-        let base = spec_bytes.iter()
-                             .map(| &byte | byte == '1' as u8)
-                             .fold(0, | acc, tf | {
-                                 if tf {
-                                     (acc << 1) | 1
-                                 } else {
-                                     acc << 1
-                                 }
-                             });
-
-        let args = arg_bytes.iter()
-                            .map(| &arg_byte | {
-                                spec_bytes.iter()
-                                          .map(| &spec_byte | arg_byte == spec_byte)
-                                          .fold(0, | acc, tf | {
-                                              if tf {
-                                                  (acc << 1) | 1
-                                              } else {
-                                                  acc << 1
-                                              }
-                                          })
-                            })
-                            .collect();
-
-        Opdef {
-            name: name.to_string(),
-            base,
-            args,
-            shift,
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CliError::Help          => 0,
+            CliError::Source(..)    => 1,
+            CliError::Compile(..)   => 1,
+            CliError::Usage(_)      => 2,
+            CliError::Io(..)        => 3,
         }
     }
+}
 
-    fn apply(&self, arg_vals: &[u64]) -> u64 {
-        // TODO check arg_vals and args same len
-        if self.shift == 0 {
-            self.args.iter()
-                     .zip(arg_vals.iter())
-                     .map(| (&mask, &arg) | mask.eat(arg))
-                     .fold(self.base, | acc, x | acc | x)
-        } else {
-            let arg = arg_vals[0] >> self.shift;
-            self.base | self.args[0].eat(arg)
+impl Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Help => write!(f, "{}", USAGE),
+            CliError::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE.trim_end()),
+            CliError::Io(path, err) => write!(f, "{}: {}", path, err),
+            CliError::Source(path, err) => write!(f, "{}:{}", path, err),
+            // positions read as file:line:col
+            CliError::Compile(path, err @ CompileError::At(..)) => write!(f, "{}:{}", path, err),
+            CliError::Compile(path, err) => write!(f, "{}: {}", path, err),
         }
     }
 }
 
-//
-
-#[derive(Copy, Clone, Debug)]
-enum HexRecordType {
-    Data,
-    EndOfFile,
-    ExtendedSegmentAddress,
-    StartSegmentAddress,
-    ExtendedLinearAddress,
-    StarLinearAddress,
+fn set_option(slot: &mut Option<String>, name: &str, value: Option<&String>) -> Result<(), CliError> {
+    let value = value.ok_or_else(|| CliError::Usage(format!("'{}' needs a value", name)))?;
+    if slot.replace(value.clone()).is_some() {
+        return Err(CliError::Usage(format!("'{}' given more than once", name)));
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug)]
-struct HexRecord {
-    typ: HexRecordType,
-    addr: u16,
-    data: Vec<u8>,
+// decimal or 0x hex
+fn parse_byte(s: &str) -> Option<u8> {
+    match s.strip_prefix("0x") {
+        Some(digits) => u8::from_str_radix(digits, 16).ok(),
+        None => s.parse().ok(),
+    }
 }
 
-impl HexRecord {
-    fn checksum(&self) -> u8 {
-        fn twos_complement(num: u16) -> u16 {
-            (num ^ u16::max_value()).wrapping_add(1)
+fn parse_args(args: &[String]) -> Result<Options, CliError> {
+    let mut input = None;
+    let mut output = None;
+    let mut device = None;
+    let mut format = None;
+    let mut listing = None;
+    let mut map = None;
+    let mut entry = None;
+    let mut fill = None;
+    let mut split = false;
+    let mut section = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => return Err(CliError::Help),
+            "-o"            => set_option(&mut output, arg, args.next())?,
+            "--device"      => set_option(&mut device, arg, args.next())?,
+            "--format"      => set_option(&mut format, arg, args.next())?,
+            "--listing"     => set_option(&mut listing, arg, args.next())?,
+            "--map"         => set_option(&mut map, arg, args.next())?,
+            "--entry"       => set_option(&mut entry, arg, args.next())?,
+            "--fill"        => set_option(&mut fill, arg, args.next())?,
+            "--split"       => split = true,
+            "--section"     => set_option(&mut section, arg, args.next())?,
+            _ if arg.starts_with('-') => {
+                return Err(CliError::Usage(format!("unknown option '{}'", arg)));
+            }
+            _ => set_option(&mut input, "input", Some(arg))?,
         }
+    }
 
-        let mut ret: u16 = 0;
+    let format =
+        match format {
+            Some(name) => {
+                name.parse()
+                    .map_err(|_| CliError::Usage(format!("unknown format '{}'", name)))?
+            }
+            None => OutputFormat::IHex,
+        };
 
-        ret = ret.wrapping_add(self.data.len() as u16);
-        ret = ret.wrapping_add(self.addr);
-        ret = ret.wrapping_add(self.addr >> 8);
-        ret = ret.wrapping_add(self.typ as u16);
-        for &byte in &self.data {
-            ret = ret.wrapping_add(byte as u16)
-        }
+    let fill =
+        match fill {
+            Some(byte) => Some(parse_byte(&byte).ok_or_else(|| CliError::Usage(format!("'{}' isnt a byte", byte)))?),
+            None => None,
+        };
 
-        (0xff & twos_complement(ret)) as u8
+    if split && format != OutputFormat::Bin {
+        return Err(CliError::Usage("--split only works with --format bin".to_string()));
     }
-}
 
-impl Display for HexRecord {
-    fn fmt(&self, f: &mut fmt::Formatter)-> fmt::Result {
-        write!(f, ":{:02x}{:04x}{:02x}",
-               self.data.len() & 0xff,
-               self.addr,
-               self.typ as u8)?;
-        for byte in &self.data {
-            write!(f, "{:02x}", *byte)?;
-        }
-        write!(f, "{:02x}", self.checksum())
-    }
+    Ok(Options {
+        input: input.ok_or_else(|| CliError::Usage("no input file".to_string()))?,
+        output,
+        device: device.unwrap_or_else(|| "avr".to_string()),
+        format,
+        listing,
+        map,
+        entry,
+        fill,
+        split,
+        section,
+    })
 }
 
-//
-
-#[derive(Clone, Debug)]
-enum IArg {
-    Raw(u64),
-    LabelAccess {
-        name: String,
-        is_relative: bool,
-        offset: i32,
+fn device_settings(name: &str) -> Option<(Isa, CompileSettings)> {
+    match name {
+        "avr" => Some((avr::instruction_set(), avr::compile_settings())),
+        _ => device::find(name).map(| device | (avr::instruction_set(), device.compile_settings())),
     }
 }
 
-impl IArg {
-    fn resolve(&self, addr: u64, label_table: &HashMap<String, u64>) -> Result<u64, CompileError> {
-        match self {
-            IArg::Raw(val) => Ok(*val),
-            IArg::LabelAccess {
-                name,
-                is_relative,
-                offset
-            } => {
-                if let Some(label_addr) = label_table.get(name) {
-                    if *is_relative {
-                        // TODO check this works
-                        Ok((*label_addr as i32
-                            - addr as i32
-                            + *offset) as u64)
-                    } else {
-                        Ok(*label_addr)
-                    }
-                } else {
-                    Err(CompileError::LabelNotFound(name.clone()))
-                }
-            },
-        }
+// what kind of symbol it is, for the map
+//   labels are named by their section
+fn symbol_kind_name(kind: SymbolKind) -> &'static str {
+    match kind {
+        SymbolKind::Address(section) => section.name(),
+        SymbolKind::Constant         => "constant",
+        SymbolKind::Register         => "register",
     }
 }
 
-#[derive(Debug)]
-struct Instruction<'a> {
-    opdef: &'a Opdef,
-    args: Vec<IArg>,
-}
+// one line per symbol, labels first
+fn symbol_map(code: &[(Position, CodeObject)], settings: &CompileSettings) -> Result<String, CompileError> {
+    let symbols = uokichi::symbol_table(settings, code)?;
 
-//
+    let order = | kind: SymbolKind | {
+        match kind {
+            SymbolKind::Address(section) => Section::ALL.iter().position(| &s | s == section).unwrap(),
+            SymbolKind::Constant         => Section::ALL.len(),
+            SymbolKind::Register         => Section::ALL.len() + 1,
+        }
+    };
 
-#[derive(Debug)]
-enum CodeObject<'a> {
-    Instruction(Instruction<'a>),
-    AddressTag(u64),
-    LabelTag(String),
+    let mut entries: Vec<(&String, &Symbol)> = symbols.iter().collect();
+    entries.sort_by_key(| (name, symbol) | (order(symbol.kind), symbol.value, name.to_string()));
+
+    Ok(entries.iter()
+              .map(| (name, symbol) | {
+                  let value =
+                      match symbol.kind {
+                          SymbolKind::Address(_) => format!("#x{:06x}", symbol.value),
+                          SymbolKind::Constant   => format!("{}", symbol.value),
+                          SymbolKind::Register   => format!("r{}", symbol.value),
+                      };
+                  format!("{:<10}  {:<8}  {}\n", value, symbol_kind_name(symbol.kind), name)
+              })
+              .collect())
 }
 
-#[derive(Debug)]
-enum HexObject {
-    AddressTag(u64),
-    Opcode(u64),
+fn listing(isa: &Isa, regions: &[(u64, Vec<u8>)], settings: &CompileSettings) -> String {
+    disasm::disassemble_regions(isa, regions, settings)
+        .iter()
+        .map(| line | format!("{}\n", line))
+        .collect()
 }
 
-// todo default
-#[derive(Clone, Debug)]
-struct CompileSettings {
-    opcode_size: u8,
-    address_size: u8,
-    eof_record: HexRecord,
-    words_per_record: u8,
+fn lines<T: Display>(records: &[T]) -> String {
+    records.iter()
+           .map(| record | format!("{}\n", record))
+           .collect()
 }
 
-#[derive(Debug)]
-enum CompileError {
-    StartWithAddressTag,
-    DuplicateLabel(String),
-    LabelNotFound(String),
+// out.bin to out-001f00.bin
+fn region_path(output: &str, addr: u64) -> String {
+    let path = Path::new(output);
+    let stem = path.file_stem()
+                   .map(| stem | stem.to_string_lossy().into_owned())
+                   .unwrap_or_default();
+    let extension = path.extension()
+                        .map(| extension | extension.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| "bin".to_string());
+    path.with_file_name(format!("{}-{:06x}.{}", stem, addr, extension))
+        .to_string_lossy()
+        .into_owned()
 }
 
-fn generate_address_image(code: &[CodeObject]) -> Result<Vec<u64>, CompileError> {
-    use CodeObject::*;
-
-    let mut offset =
-        if let AddressTag(addr) = code[0] {
-            addr
-        } else {
-            return Err(CompileError::StartWithAddressTag);
-        };
+fn write_file(path: &str, contents: &[u8]) -> Result<(), CliError> {
+    fs::write(path, contents).map_err(| err | CliError::Io(path.to_string(), err))
+}
 
-    let mut addr_image = Vec::new();
-    addr_image.reserve(code.len());
+fn run(options: &Options) -> Result<(), CliError> {
+    let (isa, mut settings) = device_settings(&options.device)
+        .ok_or_else(|| CliError::Usage(format!("unknown device '{}'", options.device)))?;
 
-    for obj in code.iter() {
-        addr_image.push(offset);
-        match obj {
-            AddressTag(addr) => offset = *addr,
-            Instruction(_)   => offset += 1,
-            _ => {}
+    settings.entry = options.entry.clone();
+    if let Some(fill) = options.fill {
+        settings.bin_fill = fill;
+    }
+    match options.section.as_deref() {
+        Some("all") => settings.output_section = None,
+        Some(name) => {
+            let section = Section::from_name(name)
+                                  .ok_or_else(|| CliError::Usage(format!("unknown section '{}'", name)))?;
+            settings.output_section = Some(section);
         }
+        None => {}
     }
 
-    Ok(addr_image)
-}
-
-fn generate_label_table(code: &[CodeObject], address_image: &[u64]) -> Result<HashMap<String, u64>, CompileError> {
-    use CodeObject::*;
+    let path = &options.input;
+    let source = fs::read_to_string(path).map_err(| err | CliError::Io(path.clone(), err))?;
+    let code = frontend::read_program(&source, &isa).map_err(| err | CliError::Source(path.clone(), err))?;
 
-    let mut label_table = HashMap::new();
-
-    for (obj, addr) in code.iter().zip(address_image.iter()) {
-        match obj {
-            LabelTag(label) => {
-                let name = label.clone();
-                if label_table.contains_key(label) {
-                    return Err(CompileError::DuplicateLabel(name));
-                } else {
-                    label_table.insert(name, *addr);
-                }
-            }
-            _ => {}
-        }
+    if let Some(map_path) = &options.map {
+        let map = symbol_map(&code, &settings).map_err(| err | CliError::Compile(path.clone(), err))?;
+        write_file(map_path, map.as_bytes())?;
     }
 
-    Ok(label_table)
-}
-
-fn generate_hex_objects(
-    code: &[CodeObject],
-    address_image: &[u64],
-    label_table: &HashMap<String, u64>
-) -> Result<Vec<HexObject>, CompileError> {
-    use CodeObject::*;
-
-    code.iter()
-        .zip(address_image.iter())
-        .filter(| (obj, _) | !matches!(obj, LabelTag(_)))
-        .map(| (obj, &addr) | {
-            match obj {
-                AddressTag(tag_addr) => Ok(HexObject::AddressTag(*tag_addr)),
-                Instruction(inst)    => {
-                    let args: Result<Vec<_>, _> =
-                        inst.args.iter()
-                                 .map(| arg | arg.resolve(addr, &label_table))
+    let output_path = options.output.clone().unwrap_or_else(|| {
+        Path::new(path).with_extension(options.format.extension())
+                       .to_string_lossy()
+                       .into_owned()
+    });
+
+    let compile_error = | err | CliError::Compile(path.clone(), err);
+    let (files, regions) =
+        match options.format {
+            OutputFormat::IHex => {
+                let records = uokichi::compile(&settings, &code).map_err(compile_error)?;
+                (vec![(output_path, lines(&records).into_bytes())], hex_regions(&records))
+            }
+            OutputFormat::SRec => {
+                let records = uokichi::compile_srec(&settings, &code).map_err(compile_error)?;
+                (vec![(output_path, lines(&records).into_bytes())], srec_regions(&records))
+            }
+            OutputFormat::Bin if options.split => {
+                let image = uokichi::compile_image(&settings, &code).map_err(compile_error)?;
+                let files = image.regions()
+                                 .map(| (addr, bytes) | (region_path(&output_path, addr), bytes.to_vec()))
                                  .collect();
-                    match args {
-                        Ok(args) => Ok(HexObject::Opcode(inst.opdef.apply(&args))),
-                        Err(err) => Err(err),
-                    }
-                }
-                _ => unreachable!(),
+                (files, image.to_regions())
             }
-        })
-        .collect()
-}
+            OutputFormat::Bin => {
+                let image = uokichi::compile_image(&settings, &code).map_err(compile_error)?;
+                let (_, flat) = flat_image(&image, settings.bin_fill);
+                (vec![(output_path, flat)], image.to_regions())
+            }
+            OutputFormat::Elf => {
+                let (elf, image) = uokichi::compile_elf(&settings, &code).map_err(compile_error)?;
+                (vec![(output_path, elf)], image.to_regions())
+            }
+        };
 
-fn generate_hex_records(hex_objects: &[HexObject], settings: &CompileSettings) -> Result<Vec<HexRecord>, CompileError> {
-    use HexObject::*;
-
-    let mut records = Vec::new();
-
-    let i_addresses = hex_objects.iter()
-                                 .filter_map(| obj | {
-                                     if let AddressTag(addr) = obj {
-                                         Some(addr)
-                                     } else {
-                                         None
-                                     }
-                                 });
-
-    // note:
-    // skips one
-    //   because hex_objects will start with an addr tag
-    //   i_splits[0] == []
-    let i_splits = hex_objects.split(| obj | matches!(obj, AddressTag(_)))
-                              .skip(1);
-
-    for (split_addr, split) in i_addresses.zip(i_splits) {
-        let i_chunks = split.chunks(settings.words_per_record as usize);
-        for (chunk_addr, chunk) in i_chunks.enumerate() {
-            records.push(HexRecord {
-                typ: HexRecordType::Data,
-                // TODO check somehow?
-                addr: (split_addr + (chunk_addr * settings.words_per_record as usize) as u64) as u16,
-                data: chunk.iter()
-                           .flat_map(| obj | {
-                               let opcode =
-                                   match obj {
-                                       Opcode(val) => val,
-                                       _ => unreachable!(),
-                                   };
-
-                               opcode.to_bytes(settings.opcode_size as usize)
-                           })
-                           .collect(),
-            });
-        }
+    for (file_path, contents) in &files {
+        write_file(file_path, contents)?;
     }
 
-    records.push(settings.eof_record.clone());
-
-    Ok(records)
-}
+    if let Some(listing_path) = &options.listing {
+        write_file(listing_path, listing(&isa, &regions, &settings).as_bytes())?;
+    }
 
-fn compile(settings: CompileSettings, code: Vec<CodeObject>) -> Result<Vec<HexRecord>, CompileError> {
-    let address_image = generate_address_image(&code)?;
-    let label_table = generate_label_table(&code, &address_image)?;
-    let hex_objects = generate_hex_objects(&code, &address_image, &label_table)?;
-    generate_hex_records(&hex_objects, &settings)
+    Ok(())
 }
 
-//
-
 fn main() {
-    let opdef_add = Opdef::new("add", "0011aabb", "ab", 0);
-    let opdef_jmp = Opdef::new("jmp", "1100aaaa", "a", 8);
-    let opdef_direct = Opdef::new("direct", "aaaaaaaa", "a", 0);
-
-    let settings = CompileSettings {
-        opcode_size: 2,
-        address_size: 2,
-        eof_record: HexRecord {
-            typ: HexRecordType::EndOfFile,
-            data: Vec::new(),
-            addr: 0,
-        },
-        words_per_record: 10
-    };
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    let lb = IArg::LabelAccess {
-        name: "start".to_string(),
-        is_relative: false,
-        offset: 0,
-    };
-
-    let c =
-        vec![
-            CodeObject::AddressTag(0xdead),
-            CodeObject::LabelTag("start".to_string()),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_add, args: vec![IArg::Raw(0b11), IArg::Raw(0b00)]} ),
-            CodeObject::AddressTag(0xdeac),
-            CodeObject::LabelTag("another".to_string()),
-            CodeObject::Instruction(Instruction { opdef: &opdef_jmp, args: vec![lb.clone()]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_direct, args: vec![lb]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_jmp, args: vec![IArg::Raw(0xdead)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_direct, args: vec![IArg::Raw(0xdead)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_jmp, args: vec![IArg::Raw(0xdead)]} ),
-            CodeObject::Instruction(Instruction { opdef: &opdef_direct, args: vec![IArg::Raw(0xdead)]} ),
-        ];
-
-    let hrs = compile(settings, c).unwrap();
-    for hr in hrs {
-        println!("{}", hr);
+    if let Err(err) = parse_args(&args).and_then(| options | run(&options)) {
+        if let CliError::Help = err {
+            print!("{}", err);
+        } else {
+            eprintln!("{}", err);
+        }
+        process::exit(err.exit_code());
     }
 }
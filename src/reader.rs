@@ -0,0 +1,336 @@
+//! s-expression reader
+
+use std::fmt;
+use std::fmt::Display;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// where something starts in the source, counting from 1
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Position {
+    /// from 1
+    pub line: usize,
+    /// from 1, in chars
+    pub col: usize,
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// what an s-expression is
+#[derive(Clone, Debug)]
+pub enum SexpKind {
+    /// decimal, or with a `#x`, `#b`, `#o`, or `#d` radix, and an optional leading `-`
+    Number(i64),
+    /// any other atom
+    Symbol(String),
+    /// bytes, so \x escapes above 7f stay one byte
+    Str(Vec<u8>),
+    /// `(...)`
+    List(Vec<Sexp>),
+}
+
+/// an s-expression and where it starts
+#[derive(Clone, Debug)]
+pub struct Sexp {
+    /// what it is
+    pub kind: SexpKind,
+    /// where it starts
+    pub pos: Position,
+}
+
+/// source that isnt s-expressions
+#[derive(Debug)]
+pub enum ReadError {
+    /// a list thats never closed, at the open paren
+    UnexpectedEof(Position),
+    /// a close paren without an open one
+    UnmatchedParen(Position),
+    /// an atom that starts like a number but isnt one
+    InvalidNumber(Position, String),
+    /// a string thats never closed, at its open quote
+    UnterminatedString(Position),
+    /// an escape thats not `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, or `\xNN`
+    InvalidEscape(Position),
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::UnexpectedEof(pos) => {
+                write!(f, "{}: unexpected end of input, list opened here is never closed", pos)
+            }
+            ReadError::UnmatchedParen(pos) => {
+                write!(f, "{}: unmatched ')'", pos)
+            }
+            ReadError::InvalidNumber(pos, token) => {
+                write!(f, "{}: invalid number '{}'", pos, token)
+            }
+            ReadError::UnterminatedString(pos) => {
+                write!(f, "{}: string is never closed", pos)
+            }
+            ReadError::InvalidEscape(pos) => {
+                write!(f, "{}: invalid escape in string", pos)
+            }
+        }
+    }
+}
+
+//
+
+struct Reader<'a> {
+    chars: Peekable<Chars<'a>>,
+    pos: Position,
+}
+
+impl<'a> Reader<'a> {
+    fn new(source: &'a str) -> Self {
+        Reader {
+            chars: source.chars().peekable(),
+            pos: Position { line: 1, col: 1 },
+        }
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let ch = self.chars.next()?;
+        if ch == '\n' {
+            self.pos.line += 1;
+            self.pos.col = 1;
+        } else {
+            self.pos.col += 1;
+        }
+        Some(ch)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(&ch) = self.chars.peek() {
+            if ch == ';' {
+                while let Some(ch) = self.next_char() {
+                    if ch == '\n' {
+                        break;
+                    }
+                }
+            } else if ch.is_whitespace() {
+                self.next_char();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn read_atom(&mut self) -> Result<Sexp, ReadError> {
+        let pos = self.pos;
+        let mut token = String::new();
+        while let Some(&ch) = self.chars.peek() {
+            if ch.is_whitespace() || ch == '(' || ch == ')' || ch == ';' || ch == '"' {
+                break;
+            }
+            token.push(ch);
+            self.next_char();
+        }
+
+        let kind =
+            if let Some(num) = parse_number(&token) {
+                SexpKind::Number(num)
+            } else if token.starts_with('#') || token.starts_with(|ch: char| ch.is_ascii_digit()) {
+                return Err(ReadError::InvalidNumber(pos, token));
+            } else {
+                SexpKind::Symbol(token)
+            };
+
+        Ok(Sexp { kind, pos })
+    }
+
+    fn read_string(&mut self) -> Result<Sexp, ReadError> {
+        let pos = self.pos;
+        self.next_char();
+
+        let mut bytes = Vec::new();
+        loop {
+            let escape_pos = self.pos;
+            match self.next_char() {
+                None => return Err(ReadError::UnterminatedString(pos)),
+                Some('"') => break,
+                Some('\\') => {
+                    let byte =
+                        match self.next_char() {
+                            Some('n')  => b'\n',
+                            Some('t')  => b'\t',
+                            Some('r')  => b'\r',
+                            Some('0')  => 0,
+                            Some('\\') => b'\\',
+                            Some('"')  => b'"',
+                            Some('x')  => {
+                                let digits: String = (0..2).filter_map(|_| self.next_char())
+                                                           .collect();
+                                u8::from_str_radix(&digits, 16).map_err(|_| ReadError::InvalidEscape(escape_pos))?
+                            }
+                            _ => return Err(ReadError::InvalidEscape(escape_pos)),
+                        };
+                    bytes.push(byte);
+                }
+                Some(ch) => {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes());
+                }
+            }
+        }
+
+        Ok(Sexp { kind: SexpKind::Str(bytes), pos })
+    }
+
+    fn read_list(&mut self) -> Result<Sexp, ReadError> {
+        let pos = self.pos;
+        self.next_char();
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                None => return Err(ReadError::UnexpectedEof(pos)),
+                Some(')') => {
+                    self.next_char();
+                    break;
+                }
+                Some(_) => items.push(self.read()?),
+            }
+        }
+
+        Ok(Sexp { kind: SexpKind::List(items), pos })
+    }
+
+    fn read(&mut self) -> Result<Sexp, ReadError> {
+        match self.chars.peek() {
+            Some('(') => self.read_list(),
+            Some(')') => Err(ReadError::UnmatchedParen(self.pos)),
+            Some('"') => self.read_string(),
+            _ => self.read_atom(),
+        }
+    }
+}
+
+fn parse_number(token: &str) -> Option<i64> {
+    let (negative, digits) =
+        if let Some(rest) = token.strip_prefix('-') {
+            (true, rest)
+        } else {
+            (false, token)
+        };
+
+    let (radix, digits) =
+        match digits.get(..2) {
+            Some("#x") | Some("#X") => (16, &digits[2..]),
+            Some("#b") | Some("#B") => (2, &digits[2..]),
+            Some("#o") | Some("#O") => (8, &digits[2..]),
+            Some("#d") | Some("#D") => (10, &digits[2..]),
+            _ => (10, digits),
+        };
+
+    if digits.is_empty() || digits.starts_with(['+', '-']) {
+        return None;
+    }
+
+    let num = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -num } else { num })
+}
+
+/// every top level expression in source
+pub fn read_all(source: &str) -> Result<Vec<Sexp>, ReadError> {
+    let mut reader = Reader::new(source);
+    let mut forms = Vec::new();
+
+    loop {
+        reader.skip_whitespace();
+        if reader.chars.peek().is_none() {
+            break;
+        }
+        forms.push(reader.read()?);
+    }
+
+    Ok(forms)
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_one(source: &str) -> Sexp {
+        read_all(source).unwrap().remove(0)
+    }
+
+    fn number(source: &str) -> i64 {
+        match read_one(source).kind {
+            SexpKind::Number(num) => num,
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    fn string(source: &str) -> Vec<u8> {
+        match read_one(source).kind {
+            SexpKind::Str(bytes) => bytes,
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn positions() {
+        let forms = read_all("; comment\n(org 0)\n  (ldi r16 ; inside\n   #xff)").unwrap();
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[0].pos, Position { line: 2, col: 1 });
+        assert_eq!(forms[1].pos, Position { line: 3, col: 3 });
+        match &forms[1].kind {
+            SexpKind::List(items) => {
+                let positions: Vec<Position> = items.iter().map(| item | item.pos).collect();
+                assert_eq!(positions, [
+                    Position { line: 3, col: 4 },
+                    Position { line: 3, col: 8 },
+                    Position { line: 4, col: 4 },
+                ]);
+            }
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn radixes() {
+        assert_eq!(number("42"), 42);
+        assert_eq!(number("-42"), -42);
+        assert_eq!(number("#xff"), 255);
+        assert_eq!(number("#XFF"), 255);
+        assert_eq!(number("-#x10"), -16);
+        assert_eq!(number("#b1010"), 10);
+        assert_eq!(number("#o17"), 15);
+        assert_eq!(number("#d99"), 99);
+        assert_eq!(number("9223372036854775807"), i64::MAX);
+
+        for token in &["#xfg", "#b2", "12ab", "#x", "#x-1", "9223372036854775808", "#q1"] {
+            assert!(matches!(read_all(token), Err(ReadError::InvalidNumber(_, bad)) if bad == *token), "{}", token);
+        }
+        // a lone minus is the operator
+        assert!(matches!(read_one("-").kind, SexpKind::Symbol(name) if name == "-"));
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(string(r#""a\tb\n\r\0""#), b"a\tb\n\r\0");
+        assert_eq!(string(r#""\\ \"""#), b"\\ \"");
+        // above 7f is still one byte, and utf-8 is kept as is
+        assert_eq!(string(r#""\x80\xFF""#), [0x80, 0xff]);
+        assert_eq!(string("\"\u{e9}\""), [0xc3, 0xa9]);
+
+        assert!(matches!(read_all(r#"(db "ab\q")"#), Err(ReadError::InvalidEscape(Position { line: 1, col: 8 }))));
+        assert!(matches!(read_all(r#""\x4""#), Err(ReadError::InvalidEscape(Position { line: 1, col: 2 }))));
+    }
+
+    #[test]
+    fn unterminated() {
+        assert!(matches!(read_all("(db 1)\n(db \"ab"), Err(ReadError::UnterminatedString(Position { line: 2, col: 5 }))));
+        assert!(matches!(read_all("(org 0)\n (db (+ 1 2)"), Err(ReadError::UnexpectedEof(Position { line: 2, col: 2 }))));
+        assert!(matches!(read_all("(org 0))"), Err(ReadError::UnmatchedParen(Position { line: 1, col: 8 }))));
+    }
+}
@@ -0,0 +1,498 @@
+//! motorola s-records
+
+use std::fmt;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::compile::CompileSettings;
+use crate::errors::CompileError;
+use crate::image::MemoryImage;
+
+/// the record types, by the digit after S
+///   start records end the file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SRecordType {
+    /// S0
+    Header,
+    /// S1
+    Data16,
+    /// S2
+    Data24,
+    /// S3
+    Data32,
+    /// S5
+    Count16,
+    /// S6
+    Count24,
+    /// S7
+    Start32,
+    /// S8
+    Start24,
+    /// S9
+    Start16,
+}
+
+impl SRecordType {
+    pub(crate) fn from_digit(digit: u8) -> Option<Self> {
+        use SRecordType::*;
+
+        match digit {
+            b'0' => Some(Header),
+            b'1' => Some(Data16),
+            b'2' => Some(Data24),
+            b'3' => Some(Data32),
+            b'5' => Some(Count16),
+            b'6' => Some(Count24),
+            b'7' => Some(Start32),
+            b'8' => Some(Start24),
+            b'9' => Some(Start16),
+            _ => None,
+        }
+    }
+
+    /// the digit after S
+    pub fn digit(self) -> char {
+        use SRecordType::*;
+
+        match self {
+            Header  => '0',
+            Data16  => '1',
+            Data24  => '2',
+            Data32  => '3',
+            Count16 => '5',
+            Count24 => '6',
+            Start32 => '7',
+            Start24 => '8',
+            Start16 => '9',
+        }
+    }
+
+    /// bytes in the address field
+    pub fn addr_len(self) -> usize {
+        use SRecordType::*;
+
+        match self {
+            Header | Data16 | Count16 | Start16 => 2,
+            Data24 | Count24 | Start24          => 3,
+            Data32 | Start32                    => 4,
+        }
+    }
+
+    /// S1, S2, and S3
+    pub fn is_data(self) -> bool {
+        use SRecordType::*;
+
+        matches!(self, Data16 | Data24 | Data32)
+    }
+
+    /// S7, S8, and S9
+    pub fn is_termination(self) -> bool {
+        use SRecordType::*;
+
+        matches!(self, Start16 | Start24 | Start32)
+    }
+}
+
+/// one s-record line
+#[derive(Clone, Debug)]
+pub struct SRecord {
+    /// what the record is
+    pub typ: SRecordType,
+    /// the record count for S5 and S6, the start address for S7 to S9
+    pub addr: u32,
+    /// bytes after the address
+    pub data: Vec<u8>,
+}
+
+impl SRecord {
+    fn addr_bytes(&self) -> Vec<u8> {
+        self.addr.to_be_bytes()[4 - self.typ.addr_len()..].to_vec()
+    }
+
+    // byte count covers the address, data, and checksum
+    fn count(&self) -> usize {
+        self.typ.addr_len() + self.data.len() + 1
+    }
+
+    /// ones complement of the byte sum
+    pub fn checksum(&self) -> u8 {
+        let sum = self.addr_bytes()
+                      .iter()
+                      .chain(self.data.iter())
+                      .fold(self.count() as u8, | acc, &byte | acc.wrapping_add(byte));
+        !sum
+    }
+}
+
+impl Display for SRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "S{}{:02X}", self.typ.digit(), self.count() & 0xff)?;
+        for byte in self.addr_bytes().iter().chain(self.data.iter()) {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, "{:02X}", self.checksum())
+    }
+}
+
+/// a line that isnt a valid s-record
+#[derive(Debug)]
+pub enum SRecParseError {
+    /// no leading S
+    MissingStartCode,
+    /// odd number of digits, or a digit thats not hex
+    InvalidHex,
+    /// less than a count, address, and checksum
+    TooShort,
+    /// count doesnt match the rest of the record
+    ByteCountMismatch {
+        /// the count in the record
+        expected: usize,
+        /// bytes after the count
+        got: usize,
+    },
+    /// checksum doesnt match
+    ChecksumMismatch {
+        /// checksum of the record
+        expected: u8,
+        /// checksum it has
+        got: u8,
+    },
+    /// S4, or not a digit
+    UnknownRecordType(char),
+    /// data in a count or start record
+    UnexpectedData(SRecordType),
+}
+
+impl Display for SRecParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use SRecParseError::*;
+
+        match self {
+            MissingStartCode => write!(f, "record doesnt start with 'S'"),
+            InvalidHex => write!(f, "record isnt valid hex"),
+            TooShort => write!(f, "record is too short"),
+            ByteCountMismatch { expected, got } => {
+                write!(f, "byte count is {} but record has {} bytes", expected, got)
+            }
+            ChecksumMismatch { expected, got } => {
+                write!(f, "checksum is {:02x}, expected {:02x}", got, expected)
+            }
+            UnknownRecordType(digit) => write!(f, "unknown record type S{}", digit),
+            UnexpectedData(typ) => write!(f, "S{} record cant have data", typ.digit()),
+        }
+    }
+}
+
+impl FromStr for SRecord {
+    type Err = SRecParseError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let rest = line.strip_prefix('S')
+                       .or_else(|| line.strip_prefix('s'))
+                       .ok_or(SRecParseError::MissingStartCode)?;
+
+        if rest.is_empty() || !rest.is_ascii() {
+            return Err(SRecParseError::TooShort);
+        }
+        let (digit, digits) = rest.split_at(1);
+        let typ = SRecordType::from_digit(digit.as_bytes()[0])
+                              .ok_or_else(|| SRecParseError::UnknownRecordType(digit.chars().next().unwrap()))?;
+
+        if digits.len() % 2 != 0 {
+            return Err(SRecParseError::InvalidHex);
+        }
+
+        let bytes: Vec<u8> = (0..digits.len()).step_by(2)
+                                              .map(| i | u8::from_str_radix(&digits[i..i + 2], 16))
+                                              .collect::<Result<_, _>>()
+                                              .map_err(|_| SRecParseError::InvalidHex)?;
+
+        // count, addr, checksum
+        if bytes.len() < 2 + typ.addr_len() {
+            return Err(SRecParseError::TooShort);
+        }
+
+        let count = bytes[0] as usize;
+        if count != bytes.len() - 1 {
+            return Err(SRecParseError::ByteCountMismatch {
+                expected: count,
+                got: bytes.len() - 1,
+            });
+        }
+
+        let addr_end = 1 + typ.addr_len();
+        let data = &bytes[addr_end..bytes.len() - 1];
+        if !typ.is_data() && typ != SRecordType::Header && !data.is_empty() {
+            return Err(SRecParseError::UnexpectedData(typ));
+        }
+
+        let record = SRecord {
+            typ,
+            addr: bytes[1..addr_end].iter()
+                                    .fold(0, | acc, &byte | (acc << 8) | byte as u32),
+            data: data.to_vec(),
+        };
+
+        let checksum = bytes[bytes.len() - 1];
+        if checksum != record.checksum() {
+            return Err(SRecParseError::ChecksumMismatch {
+                expected: record.checksum(),
+                got: checksum,
+            });
+        }
+
+        Ok(record)
+    }
+}
+
+/// a file that isnt valid s-records
+#[derive(Debug)]
+pub enum SRecReadError {
+    /// line number from 1, and the error in it
+    Line(usize, SRecParseError),
+    /// no S7, S8, or S9 record
+    MissingTermination,
+}
+
+impl Display for SRecReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SRecReadError::Line(line, err) => write!(f, "line {}: {}", line, err),
+            SRecReadError::MissingTermination => write!(f, "missing S7, S8, or S9 record"),
+        }
+    }
+}
+
+/// reads records up to and including the termination record
+pub fn read_srecords(text: &str) -> Result<Vec<SRecord>, SRecReadError> {
+    let mut records = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record: SRecord = line.parse()
+                                  .map_err(| err | SRecReadError::Line(i + 1, err))?;
+        let is_termination = record.typ.is_termination();
+        records.push(record);
+        if is_termination {
+            return Ok(records);
+        }
+    }
+
+    Err(SRecReadError::MissingTermination)
+}
+
+/// contiguous runs of data from records, at byte addresses
+pub fn srec_regions(records: &[SRecord]) -> Vec<(u64, Vec<u8>)> {
+    let mut regions: Vec<(u64, Vec<u8>)> = Vec::new();
+
+    for record in records {
+        if !record.typ.is_data() {
+            continue;
+        }
+
+        let addr = record.addr as u64;
+        match regions.last_mut() {
+            Some((start, data)) if *start + data.len() as u64 == addr => {
+                data.extend_from_slice(&record.data);
+            }
+            _ => regions.push((addr, record.data.clone())),
+        }
+    }
+
+    regions
+}
+
+/// start address from the termination record
+pub fn srec_start_address(records: &[SRecord]) -> Option<u64> {
+    records.iter()
+           .find(| record | record.typ.is_termination())
+           .map(| record | record.addr as u64)
+}
+
+//
+
+// the narrowest address that fits every byte and the entry
+//   the termination record has to match the data records
+fn address_types(end: u64) -> Option<(SRecordType, SRecordType)> {
+    if end <= 0x1_0000 {
+        Some((SRecordType::Data16, SRecordType::Start16))
+    } else if end <= 0x100_0000 {
+        Some((SRecordType::Data24, SRecordType::Start24))
+    } else if end <= 0x1_0000_0000 {
+        Some((SRecordType::Data32, SRecordType::Start32))
+    } else {
+        None
+    }
+}
+
+// entry is the byte address for the termination record
+//   without one the termination record has address 0
+pub(crate) fn generate_srecords(
+    image: &MemoryImage,
+    entry: Option<u64>,
+    settings: &CompileSettings
+) -> Result<Vec<SRecord>, CompileError> {
+    let bytes_per_record = settings.words_per_record as usize * settings.opcode_size as usize;
+
+    let end = image.span()
+                   .map(| (_, end) | end)
+                   .into_iter()
+                   .chain(entry.map(| addr | addr + 1))
+                   .max()
+                   .unwrap_or(0);
+    let (data_type, start_type) = address_types(end).ok_or(CompileError::SRecordAddressOutOfRange(end - 1))?;
+
+    let mut records = vec![SRecord {
+        typ: SRecordType::Header,
+        addr: 0,
+        data: b"uokichi".to_vec(),
+    }];
+
+    for (addr, bytes) in image.regions() {
+        for (i, chunk) in bytes.chunks(bytes_per_record).enumerate() {
+            records.push(SRecord {
+                typ: data_type,
+                addr: (addr + (i * bytes_per_record) as u64) as u32,
+                data: chunk.to_vec(),
+            });
+        }
+    }
+
+    // S6 if the count doesnt fit in S5, and no count at all if it doesnt fit in S6
+    let data_count = records.len() - 1;
+    if data_count <= 0xffff {
+        records.push(SRecord {
+            typ: SRecordType::Count16,
+            addr: data_count as u32,
+            data: Vec::new(),
+        });
+    } else if data_count <= 0xff_ffff {
+        records.push(SRecord {
+            typ: SRecordType::Count24,
+            addr: data_count as u32,
+            data: Vec::new(),
+        });
+    }
+
+    records.push(SRecord {
+        typ: start_type,
+        addr: entry.unwrap_or(0) as u32,
+        data: Vec::new(),
+    });
+
+    Ok(records)
+}
+
+//
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::avr;
+
+    fn generate(regions: &[(u64, Vec<u8>)], entry: Option<u64>, settings: &CompileSettings) -> Result<Vec<SRecord>, CompileError> {
+        generate_srecords(&MemoryImage::from_regions(regions).unwrap(), entry, settings)
+    }
+
+    // the types of the data and termination records
+    fn address_types_for(entry: Option<u64>) -> (SRecordType, SRecordType) {
+        let records = generate(&[(0, vec![1])], entry, &avr::compile_settings()).unwrap();
+        (records[1].typ, records.last().unwrap().typ)
+    }
+
+    #[test]
+    fn reads_back_what_it_writes() {
+        let bytes: Vec<u8> = (0..=255).collect();
+        let regions = [(0x10, bytes), (0x82_0000, vec![0x62, 0xd9, 0xff])];
+        let records = generate(&regions, Some(0x1234), &avr::compile_settings()).unwrap();
+        let text: String = records.iter()
+                                  .map(| record | format!("{}\n", record))
+                                  .collect();
+
+        let read = read_srecords(&text).unwrap();
+        assert_eq!(srec_regions(&read), regions);
+        assert_eq!(srec_start_address(&read), Some(0x1234));
+        assert_eq!(read.len(), records.len());
+    }
+
+    #[test]
+    fn header_names_the_assembler() {
+        let records = generate(&[(0, vec![1])], None, &avr::compile_settings()).unwrap();
+        assert_eq!(records[0].typ, SRecordType::Header);
+        assert_eq!(records[0].data, b"uokichi");
+        assert_eq!(records[0].to_string(), "S00A0000756F6B6963686909");
+        // the header isnt data
+        assert_eq!(srec_regions(&records), [(0, vec![1])]);
+    }
+
+    #[test]
+    fn count_switches_to_s6() {
+        // one word per record, so a record every two bytes
+        let mut settings = avr::compile_settings();
+        settings.words_per_record = 1;
+
+        let records = generate(&[(0, vec![0; 0x1fffe])], None, &settings).unwrap();
+        let count = &records[records.len() - 2];
+        assert_eq!((count.typ, count.addr), (SRecordType::Count16, 0xffff));
+        assert_eq!(count.to_string(), "S503FFFFFE");
+
+        let records = generate(&[(0, vec![0; 0x20000])], None, &settings).unwrap();
+        let count = &records[records.len() - 2];
+        assert_eq!((count.typ, count.addr), (SRecordType::Count24, 0x1_0000));
+        assert_eq!(count.to_string(), "S604010000FA");
+    }
+
+    #[test]
+    fn entry_alone_can_widen_addresses() {
+        use SRecordType::*;
+
+        assert_eq!(address_types_for(None), (Data16, Start16));
+        assert_eq!(address_types_for(Some(0xffff)), (Data16, Start16));
+        assert_eq!(address_types_for(Some(0x1_0000)), (Data24, Start24));
+        assert_eq!(address_types_for(Some(0xff_ffff)), (Data24, Start24));
+        assert_eq!(address_types_for(Some(0x100_0000)), (Data32, Start32));
+        let err = generate(&[(0, vec![1])], Some(0x1_0000_0000), &avr::compile_settings()).unwrap_err();
+        assert!(matches!(err, CompileError::SRecordAddressOutOfRange(0x1_0000_0000)));
+    }
+
+    #[test]
+    fn bad_records() {
+        let lines = [
+            ("X9030000FC", "record doesnt start with 'S'"),
+            ("S", "record is too short"),
+            ("S2040000FB", "record is too short"),
+            ("S4030000FC", "unknown record type S4"),
+            ("S9030000F", "record isnt valid hex"),
+            ("S90300ZZFC", "record isnt valid hex"),
+            ("S9040000FC", "byte count is 4 but record has 3 bytes"),
+            ("S9030000FB", "checksum is fb, expected fc"),
+            ("S904000001FA", "S9 record cant have data"),
+            ("S504000001FA", "S5 record cant have data"),
+        ];
+        for (line, message) in &lines {
+            assert_eq!(line.parse::<SRecord>().unwrap_err().to_string(), *message, "{}", line);
+        }
+
+        // lowercase is fine, and the S0 header can have data
+        assert_eq!("s9030000fc".parse::<SRecord>().unwrap().typ, SRecordType::Start16);
+        assert!("S00A0000756F6B6963686909".parse::<SRecord>().is_ok());
+    }
+
+    #[test]
+    fn files_end_at_a_start_record() {
+        // a count record doesnt end the file
+        let err = read_srecords("S1040000FFFC\nS5030001FB\n").unwrap_err();
+        assert!(matches!(err, SRecReadError::MissingTermination));
+
+        let err = read_srecords("S1040000FFFC\nS5030001FA\nS9030000FC\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: checksum is fa, expected fb");
+
+        // S7 and S8 end it too
+        assert_eq!(read_srecords("S70500000000FA\nS1040000FFFC\n").unwrap().len(), 1);
+        assert_eq!(read_srecords("S804000000FB\nS1040000FFFC\n").unwrap().len(), 1);
+    }
+}